@@ -0,0 +1,142 @@
+use crate::utils::{Algorithm, Distro, URL, context, unix_time};
+use crate::quickget::remote_size;
+use crate::error::QuickgetError;
+use crate::signature::signer;
+use serde::{Deserialize, Serialize};
+use itertools::Itertools;
+use rayon::prelude::*;
+use std::error::Error;
+use std::sync::OnceLock;
+
+// Bumped whenever the format changes in a way that older versions of quickget can't read.
+const CATALOG_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Catalog {
+    version: u32,
+    generated: u64,
+    entries: Vec<CatalogEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CatalogEntry {
+    os: String,
+    arch: String,
+    release: String,
+    edition: String,
+    files: Vec<CatalogFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogFile {
+    pub name: String,
+    pub urls: Vec<String>,
+    pub size: Option<u64>,
+    pub checksum: Option<String>,
+    // Missing from lockfiles written before checksums were tagged with their algorithm.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<Algorithm>,
+    // The key which signed the checksum, if its signature was checked when the lockfile was written. It's only a record,
+    // since the lockfile itself isn't signed, so checksums read from a lockfile always count as unsigned.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signed_by: Option<String>,
+}
+
+static LOCKFILE: OnceLock<Catalog> = OnceLock::new();
+
+// Resolves every release and edition of the selected distros (or all of them) and writes the result to `path`.
+// Returns the number of selections which were exported, and the number which couldn't be resolved.
+pub fn export_catalog(distros: &[Distro], path: &str, os: &str) -> Result<(usize, usize), QuickgetError> {
+    let selected = distros.iter().filter(|distro| os.is_empty() || distro.name == os).collect::<Vec<&Distro>>();
+    if selected.is_empty() {
+        return Err(QuickgetError::Unsupported { message: format!("{} is not a supported OS.", os), options: None });
+    }
+
+    let results = selected.par_iter().map(|distro| {
+        // The URLs of these distros are tied to a session and soon expire, so they can't be pinned.
+        if matches!(distro.url, URL::PlusHeaders(_)) || distro.name == "windows" {
+            eprintln!("Skipping {} ({}): its downloads can't be pinned.", distro.pretty_name, distro.arch);
+            return vec![];
+        }
+        let releases = match distro.releases() {
+            Ok(releases) => releases,
+            Err(e) => {
+                eprintln!("Unable to get releases for {} ({}): {}", distro.name, distro.arch, e);
+                return vec![Err(())];
+            },
+        };
+        releases.into_iter().flat_map(|(release, editions)| {
+            let editions = if editions.is_empty() { vec!["".to_string()] } else { editions };
+            editions.into_iter().map(move |edition| (release.clone(), edition))
+        }).map(|(release, edition)| {
+            resolve_entry(distro, &release, &edition).map_err(|e| {
+                eprintln!("Unable to resolve {} {} {} ({}): {}", distro.name, release, edition, distro.arch, e);
+            })
+        }).collect::<Vec<_>>()
+    }).flatten().collect::<Vec<Result<CatalogEntry, ()>>>();
+
+    let failed = results.iter().filter(|result| result.is_err()).count();
+    let entries = results.into_iter().flatten()
+        .sorted_by(|a, b| (&a.os, &a.arch, &a.release, &a.edition).cmp(&(&b.os, &b.arch, &b.release, &b.edition)))
+        .collect::<Vec<CatalogEntry>>();
+    let exported = entries.len();
+
+    let catalog = Catalog { version: CATALOG_VERSION, generated: unix_time(), entries };
+    std::fs::write(path, serde_json::to_string_pretty(&catalog)?).map_err(|e| QuickgetError::Io(format!("Unable to write {}: {}", path, e)))?;
+    Ok((exported, failed))
+}
+
+fn resolve_entry(distro: &Distro, release: &str, edition: &str) -> Result<CatalogEntry, Box<dyn Error>> {
+    let url_iso_list = distro.try_get_url_iso(release, edition, &distro.arch)?;
+    let sizes = context().runtime.block_on(futures::future::join_all(url_iso_list.iter().map(|(urls, headers, _)| remote_size(urls, headers))));
+    let files = url_iso_list.into_iter().zip(sizes).enumerate().map(|(index, ((urls, _, name), size))| {
+        let (algorithm, checksum) = distro.try_get_checksum(index, release, edition, &distro.arch)?.unzip();
+        let signed_by = checksum.as_deref().and_then(signer);
+        Ok(CatalogFile { name, urls, size, checksum, algorithm, signed_by })
+    }).collect::<Result<Vec<CatalogFile>, Box<dyn Error>>>()?;
+
+    Ok(CatalogEntry { os: distro.name.clone(), arch: distro.arch.clone(), release: release.into(), edition: edition.into(), files })
+}
+
+pub fn load_lockfile(path: &str) -> Result<(), QuickgetError> {
+    let data = std::fs::read(path).map_err(|e| QuickgetError::Io(format!("Unable to read {}: {}", path, e)))?;
+    let catalog: Catalog = serde_json::from_slice(&data).map_err(|e| QuickgetError::Parse(format!("Unable to parse {}: {}", path, e)))?;
+    if catalog.version != CATALOG_VERSION {
+        return Err(QuickgetError::Parse(format!("{} is a version {} lockfile, but this version of quickget can only read version {}.", path, catalog.version, CATALOG_VERSION)));
+    }
+    if LOCKFILE.set(catalog).is_err() {
+        panic!("A lockfile has already been loaded");
+    }
+    Ok(())
+}
+
+// While a lockfile is loaded, selections it contains are downloaded from the URLs and verified with the checksums it pins.
+pub fn pinned_files(os: &str, arch: &str, release: &str, edition: &str) -> Option<&'static Vec<CatalogFile>> {
+    LOCKFILE.get()?.entries.iter()
+        .find(|entry| entry.os == os && entry.arch == arch && entry.release == release && entry.edition == edition)
+        .map(|entry| &entry.files)
+}
+
+// Used instead of `validate_parameters` when downloading from a lockfile, so that no release lists need to be fetched.
+pub fn find_pinned_distro<'a>(distros: &'a [Distro], os: &str, release: &str, edition: &str, arch: &str) -> Result<&'a Distro, QuickgetError> {
+    let catalog = LOCKFILE.get().expect("A lockfile must be loaded before looking up pinned selections");
+    let entries = catalog.entries.iter().filter(|entry| entry.os == os).collect::<Vec<&CatalogEntry>>();
+    if entries.is_empty() {
+        return Err(QuickgetError::Unsupported { message: format!("{} is not pinned in the lockfile.", os), options: None });
+    }
+
+    let Some(entry) = entries.iter().find(|entry| entry.release == release && entry.edition == edition && entry.arch == arch) else {
+        return Err(QuickgetError::unsupported(
+            format!("{} {} {} ({}) is not pinned in the lockfile.", os, release, edition, arch),
+            format!(" - Pinned: {}", entries.iter().map(|entry| format!("{} {} ({})", entry.release, entry.edition, entry.arch)).join(", ")),
+        ));
+    };
+    if entry.files.iter().any(|file| file.checksum.is_none()) {
+        eprintln!("WARNING: The lockfile has no checksum for some of these files, so they can't be checked against the pinned images.");
+    }
+
+    distros.iter().find(|distro| distro.name == entry.os && distro.arch == entry.arch).ok_or_else(|| QuickgetError::Unsupported {
+        message: format!("{} ({}) is pinned in the lockfile, but isn't supported by this version of quickget.", entry.os, entry.arch),
+        options: None,
+    })
+}
@@ -4,10 +4,13 @@ mod macos;
 mod fedora;
 mod ubuntu;
 
-use crate::utils::{Distro, collect_page, FormatUrl, Checksum, URL, ReleaseEdition, Config};
+use crate::utils::{Distro, fetch_checksum, FormatUrl, Checksum, URL, ReleaseEdition, Config, Hash};
 use add_distro::{BasicDistros, AdvancedDistros};
+use crate::signature::{defer_image_signature, fetch_signature, KDE_NEON_KEYS};
 use std::error::Error;
 
+pub use macos::ChunklistError;
+
 // List of functions used to add distros
 
 // add_basic(homepage, name, pretty_name, releases, editions, url_format, checksum, arch, config)
@@ -19,6 +22,8 @@ use std::error::Error;
 // add_advanced_unique(homepage, name, pretty_name, release_editions, url, checksum, arch, config)
 // add_advanced_online(homepage, name, pretty_name, release_editions, url, checksum, arch, config)
 // add_advanced_unique_online(homepage, name, pretty_name, release_editions, url, checksum, arch, config)
+//
+// add_mirrors(name, upstream, mirrors)
 
 // Information:
 // Homepage: self-explanatory. Just the URL to the website.
@@ -49,25 +54,36 @@ use std::error::Error;
     //
     // "url": A function which takes the release, edition, and architecture as arguments and returns
     // a vector of URLs (surrounded in Ok()), or an error (surrounded in Err())
+    //
+    // "URL::Metalink(function)": Like "url", but the function returns the URLs of Metalink (RFC 5854)
+    // documents. The files are downloaded from the mirrors they list and verified against the hashes
+    // they contain, so no checksum function is needed. These distros are added with add().
 // Checksum types:
     // "Checksum::None": No checksum is used.
     // "Checksum::Normal(function)": A function takes in the release, edition, and architecture and
-    // returns the checksum of the first file downloaded (usually the ISO), or an error.
+    // returns the checksum of the first file downloaded (usually the ISO) along with its algorithm, or an error.
 //
 // arch: The architecture of the OS. Use standard names like "x86_64" or "aarch64".
 //
 // Config types:
     // "Config::None": Use the default configuration
     // Config::Addition(function): A function which takes in the ISO paths, release, edition, and
-    // architecture, and returns the lines that need to be added to the configuration file.
+    // architecture, and returns the lines that need to be added to the configuration file, or an error.
     // Config::Overwrite(function): A function which takes in the ISO paths, release, edition, and
     // architecture, and returns the entire config file. 
+//
+// Mirrors:
+    // add_mirrors takes the name of an OS which has already been added, the upstream URL prefix that
+    // its files are downloaded from, and a list of mirrors which replicate the same directory layout.
+    // If the upstream server fails, the mirrors are tried in order.
 
 pub fn distros() -> Vec<Distro> {
     let mut distros = Vec::new();
  
     distros.add_basic("https://neon.kde.org/", "kdeneon", "KDE Neon", vec!["user", "testing", "unstable", "developer"], vec![], "https:files.kde.org/neon/images/{RELEASE}/current/neon-{RELEASE}-current.iso", Checksum::Normal(kdeneon_hash), "x86_64", Config::None);
 
+    // Fedora isn't a URL::Metalink distro. Its images come from releases.json, whose SHA-256 is checked against the signed
+    // CHECKSUM file, and MirrorManager's metalinks describe repositories rather than images. Its mirrors are listed below.
     distros.add_advanced_unique_online("https://getfedora.org", "fedora", "Fedora", fedora::fedora_releases, fedora::get_fedora_urls, Checksum::Normal(fedora::fedora_checksum), "x86_64", Config::None);
     distros.add_advanced_unique_online("https://getfedora.org", "fedora", "Fedora", fedora::fedora_releases, fedora::get_fedora_urls, Checksum::Normal(fedora::fedora_checksum), "aarch64", Config::None);
 
@@ -95,6 +111,13 @@ pub fn distros() -> Vec<Distro> {
     distros.add_advanced_online("https://edubuntu.org", "edubuntu", "Edubuntu", ubuntu::edubuntu_releases, ubuntu::edubuntu_url, Checksum::Normal(ubuntu::edubuntu_checksum), "x86_64", Config::None);
     distros.add_advanced_online("https://xubuntu.org/", "xubuntu", "Xubuntu", ubuntu::xubuntu_releases, ubuntu::xubuntu_url, Checksum::Normal(ubuntu::xubuntu_checksum), "x86_64", Config::None);
     distros.add_advanced_online("https://ubuntucinnamon.org/", "ubuntu-cinnamon", "Ubuntu Cinnamon", ubuntu::ubuntu_cinnamon_releases, ubuntu::ubuntu_cinnamon_url, Checksum::Normal(ubuntu::ubuntu_cinnamon_checksum), "x86_64", Config::None);
+
+    distros.add("https://www.opensuse.org/", "opensuse", "openSUSE", ReleaseEdition::Basic(vec!["15.5".into(), "15.6".into(), "tumbleweed".into()], vec![]), URL::Metalink(opensuse_metalink), Checksum::None, "x86_64", Config::None);
+    distros.add("https://www.opensuse.org/", "opensuse", "openSUSE", ReleaseEdition::Basic(vec!["15.5".into(), "15.6".into(), "tumbleweed".into()], vec![]), URL::Metalink(opensuse_metalink), Checksum::None, "aarch64", Config::None);
+
+    distros.add_mirrors("fedora", "https://download.fedoraproject.org/pub/fedora/linux/", vec!["https://dl.fedoraproject.org/pub/fedora/linux/", "https://mirrors.kernel.org/fedora/"]);
+    distros.add_mirrors("ubuntu", "https://releases.ubuntu.com/", vec!["https://mirrors.kernel.org/ubuntu-releases/", "https://ftp.halifax.rwth-aachen.de/ubuntu-releases/"]);
+    distros.add_mirrors("ubuntu-server", "https://releases.ubuntu.com/", vec!["https://mirrors.kernel.org/ubuntu-releases/", "https://ftp.halifax.rwth-aachen.de/ubuntu-releases/"]);
     distros
 }
 
@@ -102,8 +125,22 @@ pub fn distros() -> Vec<Distro> {
 // collect_page: Takes in a URL and returns the body of the page as a string, or an error.
 // .format: Formats a string slice with the release, edition, and architecture
 
-fn kdeneon_hash(release: &str, edition: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    let body = collect_page("https:files.kde.org/neon/images/{RELEASE}/current/neon-{RELEASE}-current.sha256sum".format(release, edition, arch))?;
-    let checksum = body.split_whitespace().nth(0).ok_or("Unable to parse sha256sum from webpage.")?.to_string();
+// KDE neon signs its images rather than their checksums, so the signature is checked once the image has been downloaded.
+fn kdeneon_hash(release: &str, edition: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    let url = "https:files.kde.org/neon/images/{RELEASE}/current/neon-{RELEASE}-current.sha256sum".format(release, edition, arch);
+    // The file lists the dated image which "current" points to, so it's taken whatever its name.
+    let (_, checksum) = fetch_checksum(&url, |_| true)?;
+    match fetch_signature(&"https://files.kde.org/neon/images/{RELEASE}/current/neon-{RELEASE}-current.iso.sig".format(release, edition, arch)) {
+        Ok(signature) => defer_image_signature(&checksum.1, signature, &KDE_NEON_KEYS),
+        Err(e) => eprintln!("WARNING: Unable to fetch the signature of KDE neon {}: {}", release, e),
+    }
     Ok(checksum)
 }
+
+fn opensuse_metalink(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let url = match release {
+        "tumbleweed" => "https://download.opensuse.org/tumbleweed/iso/openSUSE-Tumbleweed-DVD-{ARCH}-Current.iso.meta4",
+        _ => "https://download.opensuse.org/distribution/leap/{RELEASE}/iso/openSUSE-Leap-{RELEASE}-DVD-{ARCH}-Media.iso.meta4",
+    };
+    Ok(vec![url.format(release, "", arch)])
+}
@@ -14,6 +14,7 @@ pub trait AdvancedDistros {
     fn add_advanced_online(&mut self, homepage: &str, name: &str, pretty_name: &str, release_editions: fn(&str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>>, url: fn(&str, &str, &str) -> Result<Vec<String>, Box<dyn Error>>, checksum: Checksum, arch: &str, config: Config);
     fn add_advanced_unique_online(&mut self, homepage: &str, name: &str, pretty_name: &str, release_editions: fn(&str) -> Result<Vec<(String, Vec<String>)>, Box<dyn Error>>, url: fn(&str, &str, &str) -> Result<Vec<String>, Box<dyn Error>>, checksum: Checksum, arch: &str, config: Config);
     fn add(&mut self, homepage: &str, name: &str, pretty_name: &str, release_edition: ReleaseEdition, url: URL, checksum: Checksum, arch: &str, config: Config);
+    fn add_mirrors(&mut self, name: &str, upstream: &str, mirrors: Vec<&str>);
 }
 
 trait FixVec<T> {
@@ -70,6 +71,12 @@ impl AdvancedDistros for Vec<Distro> {
             arch: arch.into(),
             homepage: homepage.into(),
             config,
+            mirrors: vec![],
+        });
+    }
+    fn add_mirrors(&mut self, name: &str, upstream: &str, mirrors: Vec<&str>) {
+        self.iter_mut().filter(|distro| distro.name == name).for_each(|distro| {
+            distro.mirrors.push((upstream.into(), mirrors.clone().fix()));
         });
     }
 }
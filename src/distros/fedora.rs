@@ -1,4 +1,6 @@
-use crate::utils::collect_page;
+use crate::utils::{Algorithm, Hash, checksum_from, collect_page};
+use crate::error::QuickgetError;
+use crate::signature::{check_cleartext_file, fedora_release_keys, record_signed};
 use std::error::Error;
 use serde::Deserialize;
 use itertools::Itertools;
@@ -50,7 +52,7 @@ pub fn get_fedora_urls(release: &str, edition: &str, arch: &str) -> Result<Vec<S
     }
 }
 
-pub fn fedora_checksum(release: &str, edition: &str, arch: &str) -> Result<String, Box<dyn Error>> {
+pub fn fedora_checksum(release: &str, edition: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
     let json = collect_page("https://getfedora.org/releases.json".into())?;
     let json: Vec<FedoraRelease> = serde_json::from_str(&json)?;
 
@@ -63,11 +65,39 @@ pub fn fedora_checksum(release: &str, edition: &str, arch: &str) -> Result<Strin
     })
     .find(|checksum| checksum.0.ends_with(".iso"));
 
-    match checksum {
-        Some(checksum) => match checksum {
-            (_, Some(checksum)) => Ok(checksum),
-            _ => Err(format!("Checksum is not available for Fedora {} {} {}", release, edition, arch).into())
-        },
-        None => Err(format!("Checksum is not available for Fedora {} {} {}", release, edition, arch).into())
+    let (link, checksum) = match checksum {
+        Some((link, Some(checksum))) => (link, (Algorithm::Sha256, checksum)),
+        _ => return Err(format!("Checksum is not available for Fedora {} {} {}", release, edition, arch).into()),
+    };
+    if let Err(e) = check_fedora_signature(&link, release, &checksum).map_err(QuickgetError::from) {
+        match e {
+            QuickgetError::Verification(_) => return Err(e.into()),
+            e => eprintln!("WARNING: Unable to check the signature of Fedora {} {}: {}", release, edition, e),
+        }
+    }
+    Ok(checksum)
+}
+
+// Each image directory has a cleartext signed CHECKSUM file. The checksum from releases.json is only counted as signed
+// if it matches the one in that file.
+fn check_fedora_signature(link: &str, release: &str, checksum: &Hash) -> Result<(), Box<dyn Error>> {
+    let (directory, iso) = link.rsplit_once('/').ok_or("Invalid image URL.")?;
+    let Some(keys) = fedora_release_keys(release) else {
+        eprintln!("WARNING: No signing key is pinned for Fedora {}, so its checksum can't be checked.", release);
+        return Ok(());
+    };
+    let listing = collect_page(directory.to_string() + "/")?;
+    let name = listing.split("href=\"").skip(1)
+        .filter_map(|link| link.split('"').next())
+        .find(|link| link.ends_with("-CHECKSUM"))
+        .ok_or("Unable to find the CHECKSUM file.")?;
+    let Some((text, signer)) = check_cleartext_file(&collect_page(format!("{}/{}", directory, name))?, keys, name)? else {
+        return Ok(());
+    };
+    let (_, signed) = checksum_from(name, &text, |file| file == iso)?;
+    if signed.0 != checksum.0 || !signed.1.eq_ignore_ascii_case(&checksum.1) {
+        return Err(QuickgetError::Verification(format!("The checksum of {} doesn't match the one in {}.", iso, name)).into());
     }
+    record_signed(&checksum.1, &signer);
+    Ok(())
 }
@@ -1,9 +1,15 @@
 use rand::seq::SliceRandom;
 use std::error::Error;
-use reqwest::header::{self, HeaderMap};
+use crate::utils::{context, not_in_snapshot};
+use crate::error::QuickgetError;
+use crate::quickget::{download_options, progress_bar, file_name};
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
 use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use rayon::prelude::*;
+use std::fs::{self, File};
 
 const TYPE_SID: usize = 16;
 const TYPE_K: usize = 64;
@@ -26,6 +32,10 @@ pub fn get_urls(release: &str, _: &str, _: &str) -> Result<Vec<(String, HeaderMa
         "sonoma" => ("Mac-53FDB3D8DB8CA971", "00000000000000000"),
         _ => return Err("Invalid release".into())
     };
+    // Like Windows, the image links only work with the session they were handed out for.
+    if download_options().offline {
+        return Err(not_in_snapshot("http://osrecovery.apple.com/InstallationPayload/RecoveryImage").into());
+    }
 
     let generate_id = |chars: usize| -> String {
         let characters = ['0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'A', 'B', 'C', 'D', 'E', 'F'];
@@ -43,14 +53,14 @@ pub fn get_urls(release: &str, _: &str, _: &str) -> Result<Vec<(String, HeaderMa
         ("os", "default".into()),
     ];
 
-    let reqwest = reqwest::blocking::Client::new();
+    let reqwest = &context().blocking_client;
 
     // Get session cookie, which reqwest will store.
     let session_request = reqwest.get("http://osrecovery.apple.com/")
         .header(header::HOST, "osrecovery.apple.com")
         .header(header::USER_AGENT, "InternetRecovery/1.0")
         .send()?;
-    let session_cookie = session_request.cookies().nth(0).unwrap();
+    let session_cookie = session_request.cookies().next().ok_or("Apple's recovery server didn't start a session.")?;
 
     // Send POST request to get necessary information
     let info = reqwest.post("http://osrecovery.apple.com/InstallationPayload/RecoveryImage")
@@ -63,93 +73,334 @@ pub fn get_urls(release: &str, _: &str, _: &str) -> Result<Vec<(String, HeaderMa
         .send()?
         .text()?;
 
-    let mut info = info.lines().map(|line| {
-        line.split_once(": ").unwrap_or(("",""))
-    });
+    let info = info.lines().filter_map(|line| line.split_once(": ")).collect::<Vec<(&str, &str)>>();
+    let field = |key: &str| info.iter().find(|(name, _)| *name == key).map(|(_, value)| *value)
+        .ok_or(format!("Apple's recovery server didn't send {}.", key));
 
-    let headers = |cookie: String| { 
+    let headers = |cookie: String| -> Result<HeaderMap, Box<dyn Error>> {
         let mut headers = HeaderMap::new();
-        headers.insert(header::HOST, "oscdn.apple.com".parse().unwrap());
-        headers.insert(header::CONNECTION, "close".parse().unwrap());
-        headers.insert(header::USER_AGENT, "InternetRecovery/1.0".parse().unwrap());
-        headers.insert(header::COOKIE, cookie.parse().unwrap());
-        headers
+        headers.insert(header::HOST, HeaderValue::from_static("oscdn.apple.com"));
+        headers.insert(header::CONNECTION, HeaderValue::from_static("close"));
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("InternetRecovery/1.0"));
+        headers.insert(header::COOKIE, cookie.parse()?);
+        Ok(headers)
     };
 
-    let image_link = info.find(|(key, _)| key == &INFO_IMAGE_LINK).unwrap().1;
-    let image_headers = headers(format!("AssetToken={}", info.find(|(key, _)| key == &INFO_IMAGE_SESS).unwrap().1));
+    let image_link = field(INFO_IMAGE_LINK)?;
+    let image_headers = headers(format!("AssetToken={}", field(INFO_IMAGE_SESS)?))?;
 
-    let chunklist_link = info.find(|(key, _)| key == &INFO_SIGN_LINK).unwrap().1;
-    let chunklist_headers = headers(format!("AssetToken={}", info.find(|(key, _)| key == &INFO_SIGN_SESS).unwrap().1));
+    let chunklist_link = field(INFO_SIGN_LINK)?;
+    let chunklist_headers = headers(format!("AssetToken={}", field(INFO_SIGN_SESS)?))?;
         
     println!("{}\n{:?}\n{}\n{:?}", image_link, image_headers, chunklist_link, chunklist_headers);
     Ok(vec![(image_link.to_string(), image_headers), (chunklist_link.to_string(), chunklist_headers)])
 }
 
-pub fn macos_config(_: Vec<String>, release: &str, _: &str, _: &str) -> String {
-    format!("macos_release={}{}", release, if release == "monterey" { "\ncpu_cores=2" } else { "" })
+pub fn macos_config(_: Vec<String>, release: &str, _: &str, _: &str) -> Result<String, Box<dyn Error>> {
+    Ok(format!("macos_release={}{}", release, if release == "monterey" { "\ncpu_cores=2" } else { "" }))
+}
+
+// Apple signs chunklists with the RSA keys in xnu's bsd/kern/chunklist.h, given here as hex moduli (the exponent is
+// 65537). Chunklists are fetched over plain HTTP, so one which can't be checked against a key is never trusted. Only
+// rev1_chunklist_pubkey is here so far, so chunklists signed with a later key need it given with --chunklist-key.
+const APPLE_CHUNKLIST_KEYS: &[&str] = &[
+    // rev1_chunklist_pubkey
+    concat!(
+        "C3E748CAD9CD384329E10E25A91E43E1A762FF529ADE578C935BDDF9B13F2179D4855E6FC89E9E29CA12517D17DFA1EDCE0BEBF0EA7B461FFE61D94E2BDF72C1",
+        "96F89ACD3536B644064014DAE25A15DB6BB0852ECBD120916318D1CCDEA3C84C92ED743FC176D0BACA920D3FCF3158AFF731F88CE0623182A8ED67E650515F75",
+        "745909F07D415F55FC15A35654D118C55A462D37A3ACDA08612F3F3F6571761EFCCBCC299AEE99B3A4FD6212CCFFF5EF37A2C334E871191F7E1C31960E010A54",
+        "E86FA3F62E6D6905E1CD57732410A3EB0C6B4DEFDABE9F59BF1618758C751CD56CEF851D1C0EAA1C558E37AC108DA9089863D20E2E7E4BF475EC66FE6B3EFDCF",
+    ),
+];
+
+pub fn verify_chunklist(paths: &Vec<String>, _: &str, _: &str, _: &str) -> Result<(), Box<dyn Error>> {
+    let (dmg_path, chunklist_path) = (&paths[0], &paths[1]);
+    let data = fs::read(chunklist_path).map_err(|error| ChunklistError::Io { path: chunklist_path.clone(), error })?;
+    verify_dmg(dmg_path, &data, &chunklist_keys()?)
 }
 
-pub fn verify_chunklist(paths: &Vec<String>, _: &str, _: &str, _: &str) -> bool {
-    let mut chunklist = File::open(&paths[1]).unwrap();
-    let mut buf = vec![0; 36];
-    chunklist.read_exact(&mut buf).unwrap();
+// Checks a DMG against its chunklist, which must be signed by one of `keys`.
+fn verify_dmg(dmg_path: &str, chunklist: &[u8], keys: &[RsaPublicKey]) -> Result<(), Box<dyn Error>> {
+    let chunks = parse_chunklist(chunklist, keys)?;
+
+    let dmg = File::open(dmg_path).map_err(|error| ChunklistError::Io { path: dmg_path.to_string(), error })?;
+    let size = dmg.metadata().map_err(|error| ChunklistError::Io { path: dmg_path.to_string(), error })?.len();
+    let expected = chunks.iter().map(|chunk| chunk.chunk_size as u64).sum::<u64>();
+    if size != expected {
+        return Err(ChunklistError::Size { path: dmg_path.to_string(), expected, actual: size }.into());
+    }
 
+    // Chunks are read at their offsets, so that the workers can share the file. Each worker reuses its own buffer.
+    let offsets = chunks.iter().scan(0, |offset, chunk| {
+        let start = *offset;
+        *offset += chunk.chunk_size as u64;
+        Some(start)
+    }).collect::<Vec<u64>>();
+    let progress = progress_bar(size).with_message(file_name(dmg_path));
+    let verified = chunks.par_iter().zip(offsets).enumerate().try_for_each_init(Vec::new, |buf, (index, (chunk, offset))| {
+        buf.resize(chunk.chunk_size as usize, 0);
+        read_at(&dmg, buf, offset).map_err(|error| ChunklistError::Io { path: dmg_path.to_string(), error })?;
+        if Sha256::digest(&buf[..])[..] != chunk.chunk_sha256 {
+            return Err(ChunklistError::Mismatch { path: dmg_path.to_string(), index });
+        }
+        progress.inc(chunk.chunk_size as u64);
+        Ok(())
+    });
+    progress.finish();
+    Ok(verified?)
+}
+
+// Reads the chunks from a chunklist, once its header and signature have been checked.
+fn parse_chunklist(data: &[u8], keys: &[RsaPublicKey]) -> Result<Vec<ChunklistChunk>, Box<dyn Error>> {
+    let header = data.get(..CHUNKLIST_HEADER_SIZE).ok_or(ChunklistError::BadHeader("the header is truncated".into()))?;
     let header = ChunklistHdr {
-        cl_magic: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
-        _cl_header_size: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
-        cl_file_ver: buf[8],
-        cl_chunk_method: buf[9],
-        cl_sig_method: buf[10],
-        _unused: buf[11],
-        cl_chunk_count: u64::from_le_bytes(buf[12..20].try_into().unwrap()),
-        cl_chunk_offset: u64::from_le_bytes(buf[20..28].try_into().unwrap()),
-        _cl_sig_offset: u64::from_le_bytes(buf[28..36].try_into().unwrap()),
+        cl_magic: u32::from_le_bytes(le_bytes(&header[0..])),
+        cl_header_size: u32::from_le_bytes(le_bytes(&header[4..])),
+        cl_file_ver: header[8],
+        cl_chunk_method: header[9],
+        cl_sig_method: header[10],
+        _unused: header[11],
+        cl_chunk_count: u64::from_le_bytes(le_bytes(&header[12..])),
+        cl_chunk_offset: u64::from_le_bytes(le_bytes(&header[20..])),
+        cl_sig_offset: u64::from_le_bytes(le_bytes(&header[28..])),
     };
 
-    if header.cl_magic != CHUNKLIST_MAGIC || header.cl_file_ver != CHUNKLIST_FILE_VERSION_10 || header.cl_sig_method != CHUNKLIST_SIGNATURE_METHOD_10 || header.cl_chunk_method != CHUNKLIST_CHUNK_METHOD_10 {
-        return false;
+    if header.cl_magic != CHUNKLIST_MAGIC || header.cl_header_size as usize != CHUNKLIST_HEADER_SIZE || header.cl_file_ver != CHUNKLIST_FILE_VERSION_10 || header.cl_sig_method != CHUNKLIST_SIGNATURE_METHOD_10 || header.cl_chunk_method != CHUNKLIST_CHUNK_METHOD_10 {
+        return Err(ChunklistError::BadHeader("unsupported header".into()).into());
     }
+    // The chunks are followed by the signature, which covers everything before it.
+    let chunks_end = header.cl_chunk_count.checked_mul(CHUNKLIST_CHUNK_SIZE as u64).and_then(|size| size.checked_add(header.cl_chunk_offset));
+    let (chunks, signature) = match chunks_end {
+        Some(end) if header.cl_chunk_offset >= CHUNKLIST_HEADER_SIZE as u64 && end <= header.cl_sig_offset && header.cl_sig_offset <= data.len() as u64 => {
+            (&data[header.cl_chunk_offset as usize..end as usize], header.cl_sig_offset as usize)
+        },
+        _ => return Err(ChunklistError::BadHeader("the chunks or signature lie outside the file".into()).into()),
+    };
+    check_chunklist_signature(data, signature, keys)?;
 
-    chunklist.seek(SeekFrom::Start(header.cl_chunk_offset)).unwrap();
+    Ok(chunks.chunks_exact(CHUNKLIST_CHUNK_SIZE).map(|chunk| ChunklistChunk {
+        chunk_size: u32::from_le_bytes(le_bytes(chunk)),
+        chunk_sha256: le_bytes(&chunk[4..]),
+    }).collect())
+}
 
-    let mut dmg = File::open(&paths[0]).unwrap();
-    (0..header.cl_chunk_count).all(|_| {
-        let mut buf = [0; 0x24];
-        chunklist.read_exact(&mut buf).unwrap();
-        let chunk = ChunklistChunk {
-            chunk_size: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
-            chunk_sha256: buf[4..36].try_into().unwrap(),
-        };
-        
-        let mut data = vec![0; chunk.chunk_size as usize];
-        dmg.read_exact(&mut data).unwrap();
+// The signature is stored little-endian, unlike the PKCS #1 signatures the rsa crate expects.
+fn check_chunklist_signature(data: &[u8], offset: usize, keys: &[RsaPublicKey]) -> Result<(), Box<dyn Error>> {
+    let (signed, signature) = data.split_at(offset);
+    if signature.len() != CHUNKLIST_SIGNATURE_LEN {
+        return Err(ChunklistError::BadHeader(format!("the signature is {} bytes rather than {}", signature.len(), CHUNKLIST_SIGNATURE_LEN)).into());
+    }
+    if keys.is_empty() {
+        return Err(ChunklistError::Unverifiable.into());
+    }
+    let digest = Sha256::digest(signed);
+    let signature = signature.iter().rev().copied().collect::<Vec<u8>>();
+    match keys.iter().any(|key| key.verify(Pkcs1v15Sign::new::<Sha256>(), &digest, &signature).is_ok()) {
+        true => Ok(()),
+        false => Err(ChunklistError::BadSignature.into()),
+    }
+}
+
+// Keys given with --chunklist-key replace the built-in ones, like --keyring does for OpenPGP keys.
+fn chunklist_keys() -> Result<Vec<RsaPublicKey>, QuickgetError> {
+    let paths = &download_options().chunklist_keys;
+    if paths.is_empty() {
+        return Ok(APPLE_CHUNKLIST_KEYS.iter().map(|key| parse_chunklist_key(key).expect("Built-in chunklist keys are valid")).collect());
+    }
+    paths.iter().map(|path| {
+        let key = fs::read_to_string(path).map_err(|e| QuickgetError::Io(format!("Unable to read {}: {}", path, e)))?;
+        parse_chunklist_key(&key).ok_or_else(|| QuickgetError::Parse(format!("{} isn't an RSA public key.", path)))
+    }).collect()
+}
+
+// Keys can be PEM files, or hex moduli, which is how Apple's keys are usually quoted.
+fn parse_chunklist_key(key: &str) -> Option<RsaPublicKey> {
+    let key = key.trim();
+    if key.starts_with("-----BEGIN RSA PUBLIC KEY-----") {
+        return RsaPublicKey::from_pkcs1_pem(key).ok();
+    }
+    if key.starts_with("-----BEGIN PUBLIC KEY-----") {
+        return RsaPublicKey::from_public_key_pem(key).ok();
+    }
+    let modulus = hex::decode(key.trim_start_matches("0x").split_whitespace().collect::<String>()).ok()?;
+    RsaPublicKey::new(BigUint::from_bytes_be(&modulus), BigUint::from(65537u32)).ok()
+}
+
+fn le_bytes<const N: usize>(data: &[u8]) -> [u8; N] {
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(&data[..N]);
+    bytes
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
 
-        let digest = Sha256::digest(&data);
-        digest[0..SHA256_DIGEST_LEN as usize] == chunk.chunk_sha256[0..SHA256_DIGEST_LEN as usize]
-    })
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn read_at(_: &File, _: &mut [u8], _: u64) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[derive(Debug)]
+pub enum ChunklistError {
+    Io { path: String, error: std::io::Error },
+    BadHeader(String),
+    BadSignature,
+    // There's no key to check the signature with.
+    Unverifiable,
+    // The DMG is shorter or longer than the chunks listed for it.
+    Size { path: String, expected: u64, actual: u64 },
+    Mismatch { path: String, index: usize },
 }
 
+impl std::fmt::Display for ChunklistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Io { path, error } => write!(f, "Unable to read {}: {}", path, error),
+            Self::BadHeader(reason) => write!(f, "Invalid chunklist: {}.", reason),
+            Self::BadSignature => write!(f, "The chunklist isn't signed by a known Apple key."),
+            Self::Unverifiable => write!(f, "No Apple chunklist key is known, so the chunklist's signature can't be checked."),
+            Self::Size { path, expected, actual } if actual < expected => write!(f, "{} is truncated: its chunklist covers {} bytes, but it only has {}.", path, expected, actual),
+            Self::Size { path, expected, actual } => write!(f, "{} is {} bytes, but its chunklist only covers {}.", path, actual, expected),
+            Self::Mismatch { path, index } => write!(f, "Chunk {} of {} doesn't match its chunklist.", index, path),
+        }
+    }
+}
+
+impl Error for ChunklistError {}
+
 const CHUNKLIST_MAGIC: u32 = 0x4C4B4E43;
 const CHUNKLIST_FILE_VERSION_10: u8 = 1;
 const CHUNKLIST_CHUNK_METHOD_10: u8 = 1;
 const CHUNKLIST_SIGNATURE_METHOD_10: u8 = 1;
-const SHA256_DIGEST_LEN: u32 = 32;
+const CHUNKLIST_HEADER_SIZE: usize = 0x24;
+const CHUNKLIST_CHUNK_SIZE: usize = 0x24;
+const CHUNKLIST_SIGNATURE_LEN: usize = 256;
 
 #[derive(Debug)]
 struct ChunklistHdr {
     cl_magic: u32,
-    _cl_header_size: u32,
+    cl_header_size: u32,
     cl_file_ver: u8,
     cl_chunk_method: u8,
     cl_sig_method: u8,
     _unused: u8,
     cl_chunk_count: u64,
     cl_chunk_offset: u64,
-    _cl_sig_offset: u64,
+    cl_sig_offset: u64,
 }
 #[derive(Debug)]
 struct ChunklistChunk {
     chunk_size: u32,
     chunk_sha256: [u8; 32],
 }
+
+// The fixture chunklist lists the chunks of a generated DMG, and is signed with a throwaway key by OpenSSL, then stored
+// reversed like Apple's. No real Apple chunklist is included, so the built-in keys are only checked for being usable.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::traits::PublicKeyParts;
+
+    const CHUNKLIST: &[u8] = include_bytes!("../../tests/fixtures/chunklist/test.chunklist");
+    const KEY: &str = include_str!("../../tests/fixtures/chunklist/test.pem");
+    const CHUNK_SIZES: [u32; 3] = [1024, 1024, 300];
+
+    fn test_keys() -> Vec<RsaPublicKey> {
+        vec![parse_chunklist_key(KEY).unwrap()]
+    }
+
+    // The DMG the fixture lists.
+    fn dmg() -> Vec<u8> {
+        (0..CHUNK_SIZES.iter().sum::<u32>()).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn known_chunklist() {
+        let chunks = parse_chunklist(CHUNKLIST, &test_keys()).unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.chunk_size).collect::<Vec<u32>>(), CHUNK_SIZES);
+        assert_eq!(chunks[0].chunk_sha256[..], Sha256::digest(&dmg()[..1024])[..]);
+        // The same signature the way round the rsa crate expects it.
+        let (signed, signature) = CHUNKLIST.split_at(CHUNKLIST.len() - CHUNKLIST_SIGNATURE_LEN);
+        let big_endian = [signed, &signature.iter().rev().copied().collect::<Vec<u8>>()].concat();
+        assert!(matches!(chunklist_error(parse_chunklist(&big_endian, &test_keys())), Some(ChunklistError::BadSignature)));
+    }
+
+    #[test]
+    fn apple_keys() {
+        for key in APPLE_CHUNKLIST_KEYS {
+            let key = parse_chunklist_key(key).unwrap();
+            assert_eq!(key.size(), CHUNKLIST_SIGNATURE_LEN);
+        }
+        assert!(matches!(chunklist_error(parse_chunklist(CHUNKLIST, &chunklist_keys().unwrap())), Some(ChunklistError::BadSignature)));
+    }
+
+    #[test]
+    fn bad_chunklists() {
+        let edit = |at: usize, bytes: &[u8]| {
+            let mut data = CHUNKLIST.to_vec();
+            data[at..at + bytes.len()].copy_from_slice(bytes);
+            data
+        };
+        let length = CHUNKLIST.len() as u64;
+        let cases = [
+            (CHUNKLIST[..20].to_vec(), test_keys(), ChunklistError::BadHeader("the header is truncated".into())),
+            (edit(0, b"XXXX"), test_keys(), ChunklistError::BadHeader("unsupported header".into())),
+            (edit(28, &(length + 1).to_le_bytes()), test_keys(), ChunklistError::BadHeader("the chunks or signature lie outside the file".into())),
+            ([CHUNKLIST, b"x"].concat(), test_keys(), ChunklistError::BadHeader("the signature is 257 bytes rather than 256".into())),
+            // A byte of the second chunk's hash, which the signature covers.
+            (edit(CHUNKLIST_HEADER_SIZE + CHUNKLIST_CHUNK_SIZE + 4, &[0]), test_keys(), ChunklistError::BadSignature),
+            (CHUNKLIST.to_vec(), vec![], ChunklistError::Unverifiable),
+        ];
+        for (data, keys, expected) in cases {
+            let error = chunklist_error(parse_chunklist(&data, &keys)).unwrap();
+            assert_eq!(error.to_string(), expected.to_string());
+        }
+    }
+
+    #[test]
+    fn bad_dmgs() {
+        let path = std::env::temp_dir().join(format!("qgdl-chunklist-{}.dmg", std::process::id())).to_string_lossy().into_owned();
+        let flip = |at: usize| {
+            let mut dmg = dmg();
+            dmg[at] ^= 0x01;
+            dmg
+        };
+        let size = dmg().len() as u64;
+        let cases = [
+            (dmg(), None),
+            (dmg()[..1500].to_vec(), Some(ChunklistError::Size { path: path.clone(), expected: size, actual: 1500 })),
+            ([dmg(), vec![0]].concat(), Some(ChunklistError::Size { path: path.clone(), expected: size, actual: size + 1 })),
+            (flip(0), Some(ChunklistError::Mismatch { path: path.clone(), index: 0 })),
+            (flip(1024 + 5), Some(ChunklistError::Mismatch { path: path.clone(), index: 1 })),
+            (flip(dmg().len() - 1), Some(ChunklistError::Mismatch { path: path.clone(), index: 2 })),
+        ];
+        for (dmg, expected) in cases {
+            fs::write(&path, dmg).unwrap();
+            let result = verify_dmg(&path, CHUNKLIST, &test_keys());
+            assert_eq!(chunklist_error(result).map(|error| error.to_string()), expected.map(|error| error.to_string()));
+        }
+        fs::remove_file(&path).ok();
+    }
+
+    fn chunklist_error<T>(result: Result<T, Box<dyn Error>>) -> Option<ChunklistError> {
+        result.err()?.downcast::<ChunklistError>().ok().map(|error| *error)
+    }
+}
@@ -1,10 +1,11 @@
 use std::error::Error;
-use crate::utils::{FormatUrl, collect_page};
+use crate::utils::{Algorithm, FormatUrl, Hash, checksum_from, collect_page};
+use crate::signature::{check_checksum_file, UBUNTU_KEYS};
 use itertools::Itertools;
 use serde::Deserialize;
 use rayon::prelude::*;
 
-pub fn get_ubuntu_data(os: &str, release: &str, arch: &str) -> Result<(String, String), Box<dyn Error>> {
+pub fn get_ubuntu_data(os: &str, release: &str, arch: &str) -> Result<(String, Hash, String), Box<dyn Error>> {
     let ubuntu_arch = match arch {
         "x86_64" => "amd64",
         "aarch64" => "arm64",
@@ -23,15 +24,28 @@ pub fn get_ubuntu_data(os: &str, release: &str, arch: &str) -> Result<(String, S
         _ => (".iso", "desktop"),
     };
 
-    let data = match collect_page(url.to_owned() + "SHA256SUMS") {
-        Ok(data) => data,
-        Err(_) => collect_page(url.to_owned() + "MD5SUMS")?,
+    let (data, sums) = match collect_page(url.to_owned() + "SHA256SUMS") {
+        Ok(data) => (data, url.to_owned() + "SHA256SUMS"),
+        Err(_) => (collect_page(url.to_owned() + "MD5SUMS")?, url.to_owned() + "MD5SUMS"),
     };
-    let data = data.lines().find(|line| line.contains(ubuntu_arch) && line.contains(imagetype) && line.contains(sku)).ok_or("Could not find data for architecture.")?;
-    let hash = data.split_whitespace().nth(0).ok_or("Could not parse data.")?;
-    let iso = url + data.split("*").nth(1).ok_or("Could not parse data.")?;
-
-    Ok((iso, hash.to_owned()))
+    let (iso, hash) = checksum_from(&sums, &data, |file| file.contains(ubuntu_arch) && file.contains(imagetype) && file.contains(sku))
+        .map_err(|_| "Could not find data for architecture.")?;
+
+    Ok((url + &iso, hash, sums))
+}
+
+// The checksum files are signed by Ubuntu's CD image key. Only the SHA256SUMS signature is checked, as MD5 is too weak
+// for a signature over it to mean much.
+fn get_ubuntu_checksum(os: &str, release: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    let (_, hash, sums) = get_ubuntu_data(os, release, arch)?;
+    if hash.0 == Algorithm::Sha256 {
+        let description = format!("{} {} checksums", os, release);
+        match collect_page(sums.clone() + ".gpg") {
+            Ok(signature) => check_checksum_file(&hash.1, &collect_page(sums)?, &signature, &UBUNTU_KEYS, &description)?,
+            Err(e) => eprintln!("WARNING: Unable to fetch the signature of {}: {}", description, e),
+        }
+    }
+    Ok(hash)
 }
 
 fn get_ubuntu_releases(os: &str, arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
@@ -69,8 +83,8 @@ struct Entry {
 pub fn ubuntu_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntu", release, arch)?.0])
 }
-pub fn ubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntu", release, arch)?.1)
+pub fn ubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntu", release, arch)
 }
 pub fn ubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntu", arch)
@@ -79,8 +93,8 @@ pub fn ubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn
 pub fn kubuntu_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("kubuntu", release, arch)?.0])
 }
-pub fn kubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("kubuntu", release, arch)?.1)
+pub fn kubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("kubuntu", release, arch)
 }
 pub fn kubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("kubuntu", arch)
@@ -89,8 +103,8 @@ pub fn kubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dy
 pub fn xubuntu_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("xubuntu", release, arch)?.0])
 }
-pub fn xubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("xubuntu", release, arch)?.1)
+pub fn xubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("xubuntu", release, arch)
 }
 pub fn xubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("xubuntu", arch)
@@ -99,8 +113,8 @@ pub fn xubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dy
 pub fn lubuntu_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("lubuntu", release, arch)?.0])
 }
-pub fn lubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("lubuntu", release, arch)?.1)
+pub fn lubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("lubuntu", release, arch)
 }
 pub fn lubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("lubuntu", arch)
@@ -109,8 +123,8 @@ pub fn lubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dy
 pub fn ubuntu_budgie_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntu-budgie", release, arch)?.0])
 }
-pub fn ubuntu_budgie_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntu-budgie", release, arch)?.1)
+pub fn ubuntu_budgie_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntu-budgie", release, arch)
 }
 pub fn ubuntu_budgie_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntu-budgie", arch)
@@ -119,8 +133,8 @@ pub fn ubuntu_budgie_releases(arch: &str) -> Result<(Vec<String>, Vec<String>),
 pub fn ubuntu_mate_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntu-mate", release, arch)?.0])
 }
-pub fn ubuntu_mate_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntu-mate", release, arch)?.1)
+pub fn ubuntu_mate_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntu-mate", release, arch)
 }
 pub fn ubuntu_mate_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntu-mate", arch)
@@ -129,8 +143,8 @@ pub fn ubuntu_mate_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Bo
 pub fn ubuntu_studio_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntustudio", release, arch)?.0])
 }
-pub fn ubuntu_studio_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntustudio", release, arch)?.1)
+pub fn ubuntu_studio_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntustudio", release, arch)
 }
 pub fn ubuntu_studio_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntustudio", arch)
@@ -139,8 +153,8 @@ pub fn ubuntu_studio_releases(arch: &str) -> Result<(Vec<String>, Vec<String>),
 pub fn ubuntu_cinnamon_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntucinnamon", release, arch)?.0])
 }
-pub fn ubuntu_cinnamon_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntucinnamon", release, arch)?.1)
+pub fn ubuntu_cinnamon_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntucinnamon", release, arch)
 }
 pub fn ubuntu_cinnamon_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntucinnamon", arch)
@@ -149,8 +163,8 @@ pub fn ubuntu_cinnamon_releases(arch: &str) -> Result<(Vec<String>, Vec<String>)
 pub fn ubuntu_unity_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntu-unity", release, arch)?.0])
 }
-pub fn ubuntu_unity_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntu-unity", release, arch)?.1)
+pub fn ubuntu_unity_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntu-unity", release, arch)
 }
 pub fn ubuntu_unity_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntu-unity", arch)
@@ -159,8 +173,8 @@ pub fn ubuntu_unity_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), B
 pub fn edubuntu_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("edubuntu", release, arch)?.0])
 }
-pub fn edubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("edubuntu", release, arch)?.1)
+pub fn edubuntu_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("edubuntu", release, arch)
 }
 pub fn edubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("edubuntu", arch)
@@ -169,8 +183,8 @@ pub fn edubuntu_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<d
 pub fn ubuntu_kylin_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntukylin", release, arch)?.0])
 }
-pub fn ubuntu_kylin_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntukylin", release, arch)?.1)
+pub fn ubuntu_kylin_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntukylin", release, arch)
 }
 pub fn ubuntu_kylin_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntukylin", arch)
@@ -179,8 +193,8 @@ pub fn ubuntu_kylin_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), B
 pub fn ubuntu_server_url(release: &str, _: &str, arch: &str) -> Result<Vec<String>, Box<dyn Error>> {
     Ok(vec![get_ubuntu_data("ubuntu-server", release, arch)?.0])
 }
-pub fn ubuntu_server_checksum(release: &str, _: &str, arch: &str) -> Result<String, Box<dyn Error>> {
-    Ok(get_ubuntu_data("ubuntu-server", release, arch)?.1)
+pub fn ubuntu_server_checksum(release: &str, _: &str, arch: &str) -> Result<Hash, Box<dyn Error>> {
+    get_ubuntu_checksum("ubuntu-server", release, arch)
 }
 pub fn ubuntu_server_releases(arch: &str) -> Result<(Vec<String>, Vec<String>), Box<dyn Error>> {
     get_ubuntu_releases("ubuntu-server", arch)
@@ -1,5 +1,6 @@
-use crate::utils::FormatUrl;
-use crate::quickget::handle_download;
+use crate::utils::{FormatUrl, context, not_in_snapshot};
+use crate::quickget::{download_options, handle_download, finalize_download, report_partial_download};
+use crate::error::QuickgetError;
 use rand::{Rng, thread_rng};
 use uuid::Uuid;
 use std::error::Error;
@@ -11,12 +12,16 @@ pub fn get_windows_url(release: &str, edition: &str, arch: &str) -> Result<Vec<S
         "8"|"10" => "https://www.microsoft.com/en-us/software-download/windows{RELEASE}ISO",
         _ => "https://www.microsoft.com/en-us/software-download/windows{RELEASE}",
     }.format(release, edition, arch);
+    // Download links are tied to a session on Microsoft's servers, so they can never be part of a snapshot.
+    if download_options().offline {
+        return Err(not_in_snapshot(&url).into());
+    }
 
     let firefox_release = thread_rng().gen_range(110..=124);
     let useragent = format!("Mozilla/5.0 (X11; Linux x86_64; rv:{}.0) Gecko/20100101 Firefox/{}.0", firefox_release, firefox_release);
     let sessionid = Uuid::new_v4();
 
-    let reqwest = reqwest::blocking::Client::new();
+    let reqwest = &context().blocking_client;
 
     let mut download_page_html = reqwest.get(&url)
         .header(reqwest::header::USER_AGENT, &useragent)
@@ -26,20 +31,17 @@ pub fn get_windows_url(release: &str, edition: &str, arch: &str) -> Result<Vec<S
     download_page_html.truncate(102400);
 
     let product_id = download_page_html.split("option").find_map(|value| {
-        if value.contains("value=\"") && value.contains(">Windows") {
-            let start = value.find("value=\"").unwrap() + 7;
-            let end = value.find("\">Windows").unwrap();
-            return Some(value.get(start..end).unwrap());
-        }
-        None
-    }).unwrap();
+        let start = value.find("value=\"")? + 7;
+        let end = value.find("\">Windows")?;
+        value.get(start..end)
+    }).ok_or("Unable to find the product ID on the download page.")?;
 
     reqwest.get(format!("https://vlscppe.microsoft.com/tags?org_id=y6jn8c31&session_id={}", sessionid))
         .header(reqwest::header::ACCEPT, "")
         .header(reqwest::header::USER_AGENT, &useragent)
         .send()?;
 
-    let url_segment = &url.split("/").last().unwrap();
+    let url_segment = url.rsplit('/').next().unwrap_or_default();
 
     let mut skuid_table = reqwest.post(format!("https://www.microsoft.com/en-US/api/controls/contentinclude/html?pageId=a8f8f489-4c7f-463a-9ca6-5cff94d8d041&host=www.microsoft.com&segments=software-download,{}&query=&action=getskuinformationbyproductedition&sessionId={}&productEditionId={}&sdVersion=2", url_segment, sessionid, product_id))
         .header(reqwest::header::USER_AGENT, &useragent)
@@ -51,8 +53,8 @@ pub fn get_windows_url(release: &str, edition: &str, arch: &str) -> Result<Vec<S
     skuid_table.truncate(10240);
 
     let skuid = skuid_table.lines().find(|line| line.contains(edition))
-        .unwrap()
-        .split("&quot;").nth(3).unwrap();
+        .ok_or(format!("Microsoft didn't list a SKU for {}.", edition))?
+        .split("&quot;").nth(3).ok_or("Unable to parse the SKU information.")?;
 
     let mut download_link_html = reqwest.post(format!("https://www.microsoft.com/en-US/api/controls/contentinclude/html?pageId=6e2a1789-ef16-4f27-a296-74ef7ef5d96b&host=www.microsoft.com&segments=software-download,{}&query=&action=GetProductDownloadLinksBySku&sessionId={}&skuId={}&language=English&sdVersion=2", url_segment, sessionid, skuid))
         .header(reqwest::header::USER_AGENT, &useragent)
@@ -79,37 +81,33 @@ pub fn get_windows_url(release: &str, edition: &str, arch: &str) -> Result<Vec<S
     Ok(vec![link])
 }
 
-pub fn windows_config(paths: Vec<String>, _: &str, _: &str, _: &str) -> String {
-    let path = paths.first().unwrap().split("/").nth(0).unwrap().to_string();
+pub fn windows_config(paths: Vec<String>, _: &str, _: &str, _: &str) -> Result<String, Box<dyn Error>> {
+    let path = paths.first().and_then(|path| path.split('/').next()).ok_or("No image was downloaded.")?.to_string();
     let drivers: [String; 4] = ["https://fedorapeople.org/groups/virt/virtio-win/direct-downloads/stable-virtio/virtio-win.iso".into(),
         "https://www.spice-space.org/download/windows/spice-webdavd/spice-webdavd-x64-latest.msi".into(), 
         "https://www.spice-space.org/download/windows/vdagent/vdagent-win-0.10.0/spice-vdagent-x64-0.10.0.msi".into(),
         "https://www.spice-space.org/download/windows/usbdk/UsbDk_1.0.22_x64.msi".into()];
     println!("Downloading drivers.");
 
-    std::fs::create_dir(path.clone() + "/unattended").expect("Unable to create unattended directory.");
+    std::fs::create_dir(path.clone() + "/unattended").map_err(|e| QuickgetError::Io(format!("Unable to create the unattended directory: {}", e)))?;
 
     let downloads = drivers.into_iter().map(|url| {
         let path = if url.contains("spice-space.org") {
-            path.clone() + "/unattended/" + &url.split("/").last().unwrap()
+            path.clone() + "/unattended/" + url.rsplit('/').next().unwrap_or_default()
         } else {
-            path.clone() + "/" + &url.split("/").last().unwrap()
+            path.clone() + "/" + url.rsplit('/').next().unwrap_or_default()
         };
 
-        std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                handle_download(url, path, HeaderMap::new()).await
-            })
-        })
+        let download_path = path.clone();
+        (path, context().runtime.spawn(handle_download(vec![url], download_path, HeaderMap::new(), None)))
     }).collect::<Vec<_>>();
-    downloads.into_iter().for_each(|download| {
-        if let Err(e) = download.join().expect("ERROR: Download thread panicked") {
-            eprintln!("ERROR: {}", e);
-            std::process::exit(1);
+    for (path, download) in downloads {
+        if let Err(e) = context().runtime.block_on(download).expect("ERROR: Download task panicked").and_then(|_| Ok(finalize_download(&path)?)) {
+            report_partial_download(&path);
+            return Err(e.into());
         }
-    });
-    std::fs::write(path.clone() + "/unattended/autounattend.xml", UNATTENDED_WINDOWS).unwrap();
+    }
+    std::fs::write(path.clone() + "/unattended/autounattend.xml", UNATTENDED_WINDOWS)?;
 
     match Command::new("mkisofs")
         .arg("-quiet")
@@ -123,7 +121,7 @@ pub fn windows_config(paths: Vec<String>, _: &str, _: &str, _: &str) -> String {
         };
 
     
-    return format!(r#"fixed_iso="{}""#, path.clone() + "/unattended.iso").into();
+    Ok(format!(r#"fixed_iso="{}""#, path.clone() + "/unattended.iso"))
 }
 
 
@@ -0,0 +1,131 @@
+use crate::quickget::DownloadError;
+use crate::distros::ChunklistError;
+use std::error::Error;
+
+// Errors are passed up to `main`, which prints them and exits with the status of their kind:
+//   1  The command line couldn't be understood
+//   2  The OS, release, edition or architecture isn't available
+//   3  Some distros couldn't be listed, although the others were
+//   4  A network request failed
+//   5  Data from upstream (or a lockfile) couldn't be parsed
+//   6  An image didn't match its checksum or signature
+//   7  A file couldn't be read or written, or there's no room for it
+#[derive(Debug)]
+pub enum QuickgetError {
+    Usage(String),
+    // The options are printed on stdout, so that they can be read by front-ends.
+    Unsupported { message: String, options: Option<String> },
+    Partial(String),
+    Network(String),
+    Parse(String),
+    Verification(String),
+    Io(String),
+}
+
+pub const USAGE_EXIT_CODE: i32 = 1;
+
+impl QuickgetError {
+    pub fn unsupported(message: impl Into<String>, options: impl Into<String>) -> Self {
+        Self::Unsupported { message: message.into(), options: Some(options.into()) }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Usage(_) => USAGE_EXIT_CODE,
+            Self::Unsupported { .. } => 2,
+            Self::Partial(_) => 3,
+            Self::Network(_) => 4,
+            Self::Parse(_) => 5,
+            Self::Verification(_) => 6,
+            Self::Io(_) => 7,
+        }
+    }
+
+    // Describes what was being done when the error happened, keeping its kind.
+    pub fn context(self, context: &str) -> Self {
+        let prefix = |message: String| format!("{}: {}", context, message);
+        match self {
+            Self::Usage(message) => Self::Usage(prefix(message)),
+            Self::Unsupported { message, options } => Self::Unsupported { message: prefix(message), options },
+            Self::Partial(message) => Self::Partial(prefix(message)),
+            Self::Network(message) => Self::Network(prefix(message)),
+            Self::Parse(message) => Self::Parse(prefix(message)),
+            Self::Verification(message) => Self::Verification(prefix(message)),
+            Self::Io(message) => Self::Io(prefix(message)),
+        }
+    }
+}
+
+impl std::fmt::Display for QuickgetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Usage(message) | Self::Unsupported { message, .. } | Self::Partial(message) | Self::Network(message)
+                | Self::Parse(message) | Self::Verification(message) | Self::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for QuickgetError {}
+
+impl From<std::io::Error> for QuickgetError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for QuickgetError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Network(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for QuickgetError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Parse(e.to_string())
+    }
+}
+
+impl From<DownloadError> for QuickgetError {
+    fn from(e: DownloadError) -> Self {
+        match e {
+            DownloadError::Io(e) => e.into(),
+            e => Self::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<ChunklistError> for QuickgetError {
+    fn from(e: ChunklistError) -> Self {
+        match e {
+            ChunklistError::Io { .. } => Self::Io(e.to_string()),
+            e => Self::Verification(e.to_string()),
+        }
+    }
+}
+
+// Distro functions return boxed errors. Those from the network or the filesystem keep their kind, and anything else
+// means that a page didn't contain what was expected.
+impl From<Box<dyn Error>> for QuickgetError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<QuickgetError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        let e = match e.downcast::<reqwest::Error>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<DownloadError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        let e = match e.downcast::<ChunklistError>() {
+            Ok(e) => return (*e).into(),
+            Err(e) => e,
+        };
+        match e.downcast::<std::io::Error>() {
+            Ok(e) => (*e).into(),
+            Err(e) => Self::Parse(e.to_string()),
+        }
+    }
+}
@@ -1,21 +1,72 @@
 mod utils;
 mod distros;
 mod quickget;
+mod catalog;
+mod store;
+mod error;
+mod signature;
 
 use reqwest::header::HeaderMap;
 use utils::{Validation, List};
-use quickget::{spawn_downloads, create_config, test_urls};
+use error::{QuickgetError, USAGE_EXIT_CODE};
+use quickget::{spawn_downloads, create_config, test_urls, verify_image, set_download_options, DownloadOptions};
 
 
 fn main() {
+    if let Err(e) = run() {
+        eprintln!("ERROR: {}", e);
+        if let QuickgetError::Unsupported { options: Some(options), .. } = &e {
+            println!("{}", options);
+        }
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run() -> Result<(), QuickgetError> {
     let distros = distros::distros();
-    let (os, release, edition, download_type, arch) = get_args();
+    let (os, release, edition, download_type, arch, download_options) = get_args();
+    set_download_options(download_options);
+    utils::configure_network()?;
+
+    if quickget::download_options().offline {
+        eprintln!("Offline mode: {}.", utils::describe_snapshot()?);
+    }
 
     if let DownloadType::List(json) = download_type {
-        distros.list(json);
+        return distros.list(json);
+    }
+    if let DownloadType::ExportCatalog(path) = &download_type {
+        let (exported, failed) = catalog::export_catalog(&distros, path, &os)?;
+        if exported == 0 {
+            return Err(QuickgetError::Network(format!("Nothing could be resolved, so {} is empty.", path)));
+        }
+        println!("Pinned {} selections in {}.", exported, path);
+        if failed > 0 {
+            eprintln!("WARNING: {} selections could not be resolved and were left out.", failed);
+        }
+        return Ok(());
+    }
+    match download_type {
+        DownloadType::ListImages => return store::list_images(),
+        DownloadType::PruneImages(all) => return store::prune_images(all),
+        DownloadType::ClearCache => {
+            utils::clear_cache()?;
+            println!("Cleared the page cache.");
+            return Ok(());
+        },
+        _ => (),
     }
 
-    let distro = distros.validate_parameters(&os, &release, &edition, &arch);
+    let distro = match &quickget::download_options().lockfile {
+        Some(_) if quickget::download_options().require_signature => {
+            return Err(QuickgetError::Usage("Checksums from a lockfile can't be trusted as signed, so --require-signature can't be used with --lockfile.".into()));
+        },
+        Some(lockfile) => {
+            catalog::load_lockfile(lockfile)?;
+            catalog::find_pinned_distro(&distros, &os, &release, &edition, &arch)?
+        },
+        None => distros.validate_parameters(&os, &release, &edition, &arch)?,
+    };
     let arch = &distro.arch;
 
     //println!("{:?}", distro);
@@ -24,56 +75,64 @@ fn main() {
     //println!("OS: {}, Release: {}, Edition: {}", os, release, edition);
 
 
+    if quickget::download_options().offline {
+        if let DownloadType::Normal(_) | DownloadType::Test = download_type {
+            return Err(QuickgetError::Usage("Images can't be downloaded or tested in offline mode.".into()));
+        }
+    }
+
     match download_type {
         DownloadType::Normal(vm_path) => {
-            let url_iso_list = distro.get_url_iso(&release, &edition, &arch);
+            let url_iso_list = distro.get_url_iso(&release, &edition, &arch)?;
             if vm_path.len() > 0 {
                 let vm_path = format!("{}{}/", vm_path, distro.arch);
                 std::fs::create_dir(&vm_path).unwrap_or(());
-                let paths = spawn_downloads(url_iso_list, &vm_path, &distro, &release, &edition, &arch);
-                match distro.verify_after(&paths, &release, &edition, &arch) {
-                    Some(true) => println!("Successfully verified {} image.", distro.pretty_name),
-                    Some(false) => {
-                        eprintln!("ERROR: Failed to verify {} image.", distro.pretty_name);
-                        std::process::exit(1);
-                    },
-                    None => (),
-                };
-                match create_config(&vm_path, paths, &distro, &release, &edition) {
-                    Ok(config) => println!("\nTo start your {} virtual machine, run\n    quickemu --vm {}\n",
-                                           distro.pretty_name, config),
-                    Err(e) => eprintln!("ERROR: {}", e),
-                }
+                let paths = spawn_downloads(url_iso_list, &vm_path, &distro, &release, &edition, &arch)?;
+                let config = create_config(&vm_path, paths, &distro, &release, &edition)?;
+                println!("\nTo start your {} virtual machine, run\n    quickemu --vm {}\n", distro.pretty_name, config);
             } else {
-                spawn_downloads(url_iso_list, &vm_path, &distro, &release, &edition, &arch);
+                spawn_downloads(url_iso_list, &vm_path, &distro, &release, &edition, &arch)?;
             }
         },
         DownloadType::Test => {
-            let url_iso_list = distro.get_url_iso(&release, &edition, &arch);
-            test_urls(url_iso_list);
-            std::process::exit(0);
+            let url_iso_list = distro.get_url_iso(&release, &edition, &arch)?;
+            test_urls(url_iso_list)?;
         },
         DownloadType::Show => {
-            let url_iso_list = distro.get_url_iso(&release, &edition, &arch);
+            let url_iso_list = distro.get_url_iso(&release, &edition, &arch)?;
             friendly_urls(url_iso_list);
         },
-        DownloadType::Homepage => {
-            println!("PLACEHOLDER");
-            std::process::exit(1);
+        DownloadType::Verify(image) => {
+            let Some(checksum) = distro.get_checksum(0, &release, &edition, &arch)? else {
+                return Err(QuickgetError::Verification(format!("No checksum is available for {} {} {}.", distro.pretty_name, release, edition)));
+            };
+            println!("Verifying {} with {} checksum {}", image, checksum.0, checksum.1);
+            match verify_image(&image, &checksum)? {
+                true => println!("Successfully verified {}.", image),
+                false => return Err(QuickgetError::Verification(format!("{} does not match the expected checksum.", image))),
+            }
+            match signature::check_image(&image, &checksum.1)? {
+                Some(signer) => println!("The checksum is signed by {}.", signer),
+                None if quickget::download_options().require_signature => {
+                    return Err(QuickgetError::Verification(format!("The checksum of {} isn't signed by a trusted key.", image)));
+                },
+                None => (),
+            }
         },
-        _ => {
-            eprintln!("ERROR: Invalid download type.");
-            std::process::exit(1);
+        DownloadType::Homepage => {
+            return Err(QuickgetError::Usage(format!("Opening {}'s homepage ({}) isn't supported yet.", distro.pretty_name, distro.homepage)));
         },
+        _ => return Err(QuickgetError::Usage("Invalid download type.".into())),
     }
-
+    Ok(())
 }
 
-fn get_args() -> (String, String, String, DownloadType, String) {
+fn get_args() -> (String, String, String, DownloadType, String, DownloadOptions) {
     let mut args: Vec<String> = std::env::args().skip(1).collect();
     let mut download_type = DownloadType::None;
     let mut osinfo = Vec::new();
     let mut arch = std::env::consts::ARCH.to_string();
+    let mut download_options = DownloadOptions::default();
 
     while let Some(arg) = args.get(0) {
         match arg.as_str() {
@@ -87,7 +146,158 @@ fn get_args() -> (String, String, String, DownloadType, String) {
                     arch = args.remove(1).to_string();
                 } else {
                     eprintln!("ERROR: No architecture specified.");
-                    usage(1);
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--connections" | "-c" => {
+                match args.get(1).and_then(|connections| connections.parse::<u64>().ok()) {
+                    Some(connections) if connections > 0 => {
+                        download_options.connections = connections;
+                        args.remove(1);
+                    },
+                    _ => {
+                        eprintln!("ERROR: The number of connections must be a positive integer.");
+                        usage(USAGE_EXIT_CODE);
+                    },
+                }
+            },
+            "--retries" => {
+                match args.get(1).and_then(|retries| retries.parse::<u32>().ok()) {
+                    Some(retries) => {
+                        download_options.retries = retries;
+                        args.remove(1);
+                    },
+                    None => {
+                        eprintln!("ERROR: The number of retries must be a non-negative integer.");
+                        usage(USAGE_EXIT_CODE);
+                    },
+                }
+            },
+            "--mmap" => download_options.mmap = true,
+            "--mirror" => {
+                if args.len() > 1 {
+                    download_options.mirror = Some(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No mirror specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--fastest-mirror" => download_options.fastest_mirror = true,
+            "--refresh" => download_options.refresh = true,
+            "--offline" => download_options.offline = true,
+            "--no-image-store" => download_options.image_store = false,
+            "--require-signature" => download_options.require_signature = true,
+            "--refresh-keys" => download_options.refresh_keys = true,
+            "--keyring" => {
+                if args.len() > 1 {
+                    download_options.keyrings.push(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No keyring specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--chunklist-key" => {
+                if args.len() > 1 {
+                    download_options.chunklist_keys.push(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No chunklist key specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--limit-rate" => {
+                match args.get(1).and_then(|rate| parse_rate(rate)) {
+                    Some(rate) if rate > 0 => {
+                        download_options.limit_rate = Some(rate);
+                        args.remove(1);
+                    },
+                    _ => {
+                        eprintln!("ERROR: The rate limit must be a positive number of bytes per second, optionally followed by K, M or G.");
+                        usage(USAGE_EXIT_CODE);
+                    },
+                }
+            },
+            "--proxy" => {
+                if args.len() > 1 {
+                    download_options.proxy = Some(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No proxy specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--no-proxy" => {
+                if args.len() > 1 {
+                    download_options.no_proxy = Some(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No hosts specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--cacert" => {
+                if args.len() > 1 {
+                    download_options.ca_bundles.push(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No CA bundle specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--max-transfers" => {
+                match args.get(1).and_then(|transfers| transfers.parse::<usize>().ok()) {
+                    Some(transfers) if transfers > 0 => {
+                        download_options.max_transfers = Some(transfers);
+                        args.remove(1);
+                    },
+                    _ => {
+                        eprintln!("ERROR: The number of concurrent transfers must be a positive integer.");
+                        usage(USAGE_EXIT_CODE);
+                    },
+                }
+            },
+            "verify" => {
+                if args.len() > 1 {
+                    download_type = DownloadType::Verify(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No image specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "images" => {
+                match args.get(1).map(String::as_str) {
+                    Some("list") => download_type = DownloadType::ListImages,
+                    Some("prune") if args.get(2).map(String::as_str) == Some("--all") => {
+                        download_type = DownloadType::PruneImages(true);
+                        args.remove(2);
+                    },
+                    Some("prune") => download_type = DownloadType::PruneImages(false),
+                    _ => {
+                        eprintln!("ERROR: Unknown images command. Use 'images list' or 'images prune [--all]'.");
+                        usage(USAGE_EXIT_CODE);
+                    },
+                }
+                args.remove(1);
+            },
+            "export-catalog" => {
+                if args.len() > 1 {
+                    download_type = DownloadType::ExportCatalog(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No lockfile specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "--lockfile" => {
+                if args.len() > 1 {
+                    download_options.lockfile = Some(args.remove(1));
+                } else {
+                    eprintln!("ERROR: No lockfile specified.");
+                    usage(USAGE_EXIT_CODE);
+                }
+            },
+            "cache" => {
+                if args.get(1).map(String::as_str) == Some("clear") {
+                    download_type = DownloadType::ClearCache;
+                    args.remove(1);
+                } else {
+                    eprintln!("ERROR: Unknown cache command. The only cache command is 'clear'.");
+                    usage(USAGE_EXIT_CODE);
                 }
             },
             "list" | "list_csv" => download_type = DownloadType::List(false),
@@ -110,7 +320,18 @@ fn get_args() -> (String, String, String, DownloadType, String) {
 
     //println!("{:?}", osinfo);
 
-    (osinfo[0].to_lowercase(), osinfo[1].clone(), osinfo[2..].join(" "), download_type, arch.into())
+    (osinfo[0].to_lowercase(), osinfo[1].clone(), osinfo[2..].join(" "), download_type, arch.into(), download_options)
+}
+
+// Rates are given in bytes per second, with optional binary suffixes, like curl's --limit-rate.
+fn parse_rate(rate: &str) -> Option<u64> {
+    let (number, multiplier) = match rate.chars().last()?.to_ascii_uppercase() {
+        'K' => (&rate[..rate.len() - 1], 1 << 10),
+        'M' => (&rate[..rate.len() - 1], 1 << 20),
+        'G' => (&rate[..rate.len() - 1], 1 << 30),
+        _ => (rate, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
 }
 
 enum DownloadType {
@@ -119,14 +340,18 @@ enum DownloadType {
     Test,
     Show,
     Homepage,
+    Verify(String),
     List(bool),
+    ClearCache,
+    ExportCatalog(String),
+    ListImages,
+    PruneImages(bool),
 }
 
 fn usage(status: i32) {
     std::process::exit(status);
 }
 
-fn friendly_urls(url_iso_list: Vec<(String, HeaderMap, String)>) {
-    println!("{}", url_iso_list.iter().map(|(url, ..)| url.to_string()).collect::<Vec<_>>().join("\n"));
-    std::process::exit(0);
+fn friendly_urls(url_iso_list: Vec<(Vec<String>, HeaderMap, String)>) {
+    println!("{}", url_iso_list.iter().map(|(urls, ..)| urls[0].to_string()).collect::<Vec<_>>().join("\n"));
 }
@@ -1,112 +1,947 @@
 // This file contains the logic used for downloading files, 
 // as well as for the VM creation.
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::{Client, header::HeaderMap};
-use crate::utils::{Distro, Config};
-use std::fs;
+use reqwest::{Client, Response, StatusCode, header::{HeaderMap, RANGE, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ACCEPT_RANGES}};
+use crate::utils::{Distro, Config, Algorithm, Hash, backoff, retry_after, context};
+use crate::store::{store_key, find_image, place_image, add_image, remove_image};
+use crate::catalog::pinned_files;
+use crate::error::QuickgetError;
+use crate::signature::{self, has_signature};
+use std::fs::{self, File};
+use std::io::Read;
+use memmap2::Mmap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Semaphore;
+use tokio::sync::oneshot::{self, error::TryRecvError};
 use sha1::Sha1;
-use sha2::{Sha256, Sha512, Digest};
+use sha2::{Sha224, Sha256, Sha384, Sha512, Digest};
+use sha3::{Sha3_256, Sha3_384, Sha3_512};
+use blake2::Blake2b512;
 use md5::Md5;
 use std::error::Error;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-pub async fn handle_download(url: String, vm_path: String, headermap: HeaderMap) -> Result<String, std::io::Error> {
-    let client = Client::new();
-    let path = std::env::current_dir()?.join(vm_path.clone());
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    pub connections: u64,
+    pub mmap: bool,
+    pub retries: u32,
+    pub mirror: Option<String>,
+    pub fastest_mirror: bool,
+    // Combined rate of all transfers, in bytes per second.
+    pub limit_rate: Option<u64>,
+    pub max_transfers: Option<usize>,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<String>,
+    pub ca_bundles: Vec<String>,
+    // Ignore cached pages and fetch everything again.
+    pub refresh: bool,
+    // Answer everything from cached pages, without touching the network.
+    pub offline: bool,
+    pub lockfile: Option<String>,
+    pub image_store: bool,
+    // Refuse images whose checksums aren't signed by a trusted key.
+    pub require_signature: bool,
+    // Trusted instead of the built-in signing keys.
+    pub keyrings: Vec<String>,
+    // Fetch newer copies of the built-in signing keys.
+    pub refresh_keys: bool,
+    // Trusted instead of the built-in keys for macOS chunklists.
+    pub chunklist_keys: Vec<String>,
+}
 
-    let request = client.get(url).headers(headermap).send().await
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Unable to send request"))?;
-    let file_size = request.content_length().unwrap_or(0);
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self { connections: 1, mmap: false, retries: 5, mirror: None, fastest_mirror: false, limit_rate: None, max_transfers: None, proxy: None, no_proxy: None, ca_bundles: vec![], refresh: false, offline: false, lockfile: None, image_store: true, require_signature: false, keyrings: vec![], refresh_keys: false, chunklist_keys: vec![] }
+    }
+}
 
-    let progress = ProgressBar::new(file_size);
-    progress.set_style(ProgressStyle::with_template("[{elapsed}] {bar:40} {eta_precise} {decimal_bytes}/{decimal_total_bytes}  -   {decimal_bytes_per_sec}")
-        .unwrap().progress_chars("##-"));
+const VERIFY_BLOCK_SIZE: usize = 4_194_304;
+// Room to leave for the qcow2 disk that quickemu creates next to the images. It starts small,
+// but grows quickly once an OS is installed.
+const DISK_IMAGE_MARGIN: u64 = 4_294_967_296;
+
+static DOWNLOAD_OPTIONS: OnceLock<DownloadOptions> = OnceLock::new();
+
+pub fn set_download_options(options: DownloadOptions) {
+    DOWNLOAD_OPTIONS.set(options).expect("Download options have already been set");
+}
+
+pub fn download_options() -> &'static DownloadOptions {
+    DOWNLOAD_OPTIONS.get_or_init(DownloadOptions::default)
+}
+
+// Shared by every download in the process, including those running on other threads and runtimes.
+static RATE_LIMITER: OnceLock<Mutex<(f64, Instant)>> = OnceLock::new();
+static TRANSFER_SLOTS: OnceLock<Semaphore> = OnceLock::new();
+
+// A token bucket holding up to one second's worth of bytes. Transfers take what they've received
+// from the bucket and, once it's empty, sleep off the debt, which keeps their combined rate at the limit.
+async fn throttle(bytes: usize) {
+    let Some(rate) = download_options().limit_rate else {
+        return;
+    };
+    let rate = rate as f64;
+    let delay = {
+        let mut bucket = RATE_LIMITER.get_or_init(|| Mutex::new((rate, Instant::now()))).lock().unwrap();
+        let (tokens, refilled) = &mut *bucket;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * rate).min(rate) - bytes as f64;
+        *refilled = now;
+        Duration::from_secs_f64((-*tokens).max(0.0) / rate)
+    };
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+fn transfer_slots() -> &'static Semaphore {
+    TRANSFER_SLOTS.get_or_init(|| Semaphore::new(download_options().max_transfers.unwrap_or(Semaphore::MAX_PERMITS)))
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Request(reqwest::Error),
+    Status { url: String, status: StatusCode, retry_after: Option<Duration> },
+    Incomplete { url: String, expected: u64, received: u64 },
+    Html { url: String },
+    Io(std::io::Error),
+}
+
+impl DownloadError {
+    // Connection problems, timeouts, server errors and rate limiting are worth another attempt.
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Request(e) => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            Self::Status { status, .. } => status.is_server_error() || *status == StatusCode::TOO_MANY_REQUESTS,
+            Self::Incomplete { .. } => true,
+            Self::Html { .. } | Self::Io(_) => false,
+        }
+    }
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Status { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Request(e) => write!(f, "Request failed: {}", e),
+            Self::Status { url, status, .. } => write!(f, "Server responded with {} for {}", status, url),
+            Self::Incomplete { url, expected, received } => write!(f, "Download of {} ended after {} of {} bytes", url, received, expected),
+            Self::Html { url } => write!(f, "{} returned a web page rather than the expected image", url),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for DownloadError {}
+
+impl From<std::io::Error> for DownloadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for DownloadError {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Request(e)
+    }
+}
+
+// Decides whether a failed attempt should be retried, and if so, how long to wait before trying again.
+fn retry_delay(e: &DownloadError, failures: &mut u32, description: &str) -> Option<Duration> {
+    let retries = download_options().retries;
+    if !e.is_transient() || *failures >= retries {
+        return None;
+    }
+    let delay = e.retry_after().unwrap_or_else(|| backoff(*failures));
+    *failures += 1;
+    eprintln!("{} while downloading {}. Retrying in {:.1}s ({}/{}).", e, description, delay.as_secs_f64(), failures, retries);
+    Some(delay)
+}
+
+// The file is written to `vm_path` with a ".part" suffix, which should be renamed with `finalize_download`
+// once the download has been verified.
+// Each of `urls` should serve the same file. They're tried in order until one of them succeeds.
+// The expected checksum is sent through `checksum` once it has been fetched. If it arrives while the file
+// is still streaming, the returned digest is computed on the fly; otherwise no digest is returned.
+pub async fn handle_download(urls: Vec<String>, vm_path: String, headermap: HeaderMap, checksum: Option<oneshot::Receiver<Hash>>) -> Result<(String, Option<String>), DownloadError> {
+    // Segmented downloads count as a single transfer.
+    let _slot = transfer_slots().acquire().await.expect("The transfer semaphore is never closed");
+    let client = &context().client;
+    let path = std::env::current_dir()?.join(part_path(&vm_path));
+
+    // A segmented download writes out of order, so a file it left behind can't be resumed.
+    let marker = segment_marker(&path);
+    if tokio::fs::try_exists(&marker).await.unwrap_or(false) {
+        println!("Discarding incomplete segmented download of {}.", vm_path);
+        tokio::fs::remove_file(&path).await.ok();
+        tokio::fs::remove_file(&marker).await?;
+    }
+
+    let progress = progress_bar(0).with_message(file_name(&vm_path));
+    let mut hasher = StreamHasher { receiver: checksum, checksum: None, hasher: None, hashed: 0 };
+    let urls = match download_options().fastest_mirror && urls.len() > 1 {
+        true => sort_by_latency(client, urls, &headermap).await,
+        false => urls,
+    };
+
+    let mut result = Ok(());
+    for (index, url) in urls.iter().enumerate() {
+        if index > 0 {
+            println!("Trying mirror {}", url);
+        }
+        let mut failures = 0;
+        result = loop {
+            match download_attempt(client, url, &vm_path, &path, &headermap, &progress, &mut hasher).await {
+                Err(e) => match retry_delay(&e, &mut failures, &vm_path) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => break Err(e),
+                },
+                result => break result,
+            }
+        };
+        match &result {
+            Err(e) if index + 1 < urls.len() => eprintln!("{}. Falling back to the next mirror.", e),
+            _ => break,
+        }
+    }
+    match result {
+        Ok(_) => progress.finish(),
+        Err(_) => progress.abandon(),
+    }
+    result?;
+    Ok((vm_path, hasher.finalize()))
+}
+
+// Orders mirrors by how quickly they respond to a HEAD request. Mirrors which can't be reached are tried last.
+async fn sort_by_latency(client: &Client, urls: Vec<String>, headermap: &HeaderMap) -> Vec<String> {
+    let latencies = futures::future::join_all(urls.iter().map(|url| async move {
+        let start = Instant::now();
+        match client.head(url).headers(headermap.clone()).timeout(Duration::from_secs(10)).send().await {
+            Ok(response) if response.status().is_success() => start.elapsed(),
+            _ => Duration::MAX,
+        }
+    })).await;
+
+    let mut ranked = urls.into_iter().zip(latencies).collect::<Vec<_>>();
+    ranked.sort_by_key(|(_, latency)| *latency);
+    println!("Using the fastest mirror, {}", ranked[0].0);
+    ranked.into_iter().map(|(url, _)| url).collect()
+}
+
+// Each attempt continues from whatever is already in the partial file, so retries resume rather than restart.
+async fn download_attempt(client: &Client, url: &str, vm_path: &str, path: &Path, headermap: &HeaderMap, progress: &ProgressBar, hasher: &mut StreamHasher) -> Result<(), DownloadError> {
+    let existing = tokio::fs::metadata(path).await.map(|metadata| metadata.len()).unwrap_or(0);
+    let mut request = send_request(client, url, headermap, existing).await?;
+
+    let offset = match request.status() {
+        StatusCode::PARTIAL_CONTENT if existing > 0 && range_start(request.headers()) == Some(existing) => {
+            println!("Resuming download of {} from {:.2} MiB", vm_path, existing as f64 / 1_048_576.0);
+            existing
+        },
+        // A range starting anywhere else can't be appended to the partial file.
+        StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE if existing > 0 => {
+            if request.status() == StatusCode::RANGE_NOT_SATISFIABLE && range_total(request.headers()) == Some(existing) {
+                println!("{} has already been downloaded.", vm_path);
+                hasher.discard();
+                return Ok(());
+            }
+            println!("Unable to resume download of {}. Restarting.", vm_path);
+            request = send_request(client, url, headermap, 0).await?;
+            0
+        },
+        _ => {
+            if existing > 0 {
+                println!("Server does not support resuming downloads. Restarting download of {}.", vm_path);
+            }
+            0
+        },
+    };
+    check_status(&request)?;
+    // Only part of the file would be written, and the length check below would still pass.
+    if offset == 0 && request.status() == StatusCode::PARTIAL_CONTENT {
+        return Err(DownloadError::Status { url: url.to_string(), status: request.status(), retry_after: None });
+    }
+
+    // Error pages are sometimes served with a successful status code, so make sure we aren't about to save one as an image.
+    let expects_image = offset == 0 && [".iso", ".img", ".dmg"].iter().any(|extension| vm_path.ends_with(extension));
+    if expects_image && is_html_content_type(request.headers()) {
+        return Err(DownloadError::Html { url: url.to_string() });
+    }
+
+    let content_length = request.content_length();
+    let file_size = offset + content_length.unwrap_or(0);
+    progress.set_length(file_size);
+    progress.set_position(offset);
+
+    let connections = download_options().connections;
+    if connections > 1 && offset == 0 {
+        if supports_ranges(request.headers()) && file_size >= connections {
+            drop(request);
+            hasher.discard();
+            if let Err(e) = segmented_download(client, url, headermap, path, file_size, connections, progress).await {
+                tokio::fs::remove_file(path).await.ok();
+                tokio::fs::remove_file(segment_marker(path)).await.ok();
+                return Err(e);
+            }
+            return Ok(());
+        }
+        println!("Server does not support ranged requests. Downloading {} over a single connection.", vm_path);
+    }
 
     let mut stream = request.bytes_stream();
-    let mut file = tokio::fs::File::create(&path).await.expect("Unable to create file");
+    let mut file = if offset > 0 {
+        tokio::fs::OpenOptions::new().append(true).open(path).await?
+    } else {
+        tokio::fs::File::create(path).await?
+    };
 
-    while let Some(Ok(chunk)) = futures::StreamExt::next(&mut stream).await {
-        tokio::io::AsyncWriteExt::write_all(&mut file, &chunk).await?;
+    let mut written = offset;
+    while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+        let chunk = chunk?;
+        if expects_image && written == 0 && looks_like_html(&chunk) {
+            drop(file);
+            tokio::fs::remove_file(path).await?;
+            return Err(DownloadError::Html { url: url.to_string() });
+        }
+        hasher.poll(&mut file, path, written).await?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
         progress.inc(chunk.len() as u64);
+        throttle(chunk.len()).await;
+    }
+    file.sync_all().await?;
+
+    if let Some(expected) = content_length {
+        if written - offset != expected {
+            return Err(DownloadError::Incomplete { url: url.to_string(), expected: file_size, received: written });
+        }
+    }
+    hasher.poll(&mut file, path, written).await?;
+    Ok(())
+}
+
+fn check_status(request: &Response) -> Result<(), DownloadError> {
+    let status = request.status();
+    if !status.is_success() {
+        return Err(DownloadError::Status { url: request.url().to_string(), status, retry_after: retry_after(request.headers()) });
+    }
+    Ok(())
+}
+
+fn is_html_content_type(headers: &HeaderMap) -> bool {
+    headers.get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.trim_start().to_lowercase().starts_with("text/html"))
+}
+
+fn looks_like_html(data: &[u8]) -> bool {
+    let start = String::from_utf8_lossy(&data[..data.len().min(512)]).trim_start().to_lowercase();
+    start.starts_with("<!doctype html") || start.starts_with("<html")
+}
+
+struct StreamHasher {
+    receiver: Option<oneshot::Receiver<Hash>>,
+    checksum: Option<Hash>,
+    hasher: Option<Hasher>,
+    hashed: u64,
+}
+
+impl StreamHasher {
+    // Once the expected checksum is available, start hashing, catching up on the bytes already written to disk.
+    // The same happens if the download restarted, so that the digest always covers exactly what's in the file.
+    async fn poll(&mut self, file: &mut tokio::fs::File, path: &Path, written: u64) -> Result<(), std::io::Error> {
+        if let Some(receiver) = &mut self.receiver {
+            match receiver.try_recv() {
+                Ok(checksum) => {
+                    self.receiver = None;
+                    self.checksum = Some(checksum);
+                },
+                Err(TryRecvError::Closed) => self.receiver = None,
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+        let Some((algorithm, _)) = &self.checksum else {
+            return Ok(());
+        };
+        if self.hasher.is_none() || self.hashed != written {
+            let mut hasher = Hasher::new(*algorithm);
+            file.flush().await?;
+            hasher.update_from_file(path, written).await?;
+            self.hasher = Some(hasher);
+            self.hashed = written;
+        }
+        Ok(())
+    }
+    fn update(&mut self, chunk: &[u8]) {
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(chunk);
+            self.hashed += chunk.len() as u64;
+        }
+    }
+    // Used when the file wasn't streamed in order, so the caller has to verify it from disk instead.
+    fn discard(&mut self) {
+        self.receiver = None;
+        self.checksum = None;
+        self.hasher = None;
+    }
+    fn finalize(self) -> Option<String> {
+        self.hasher.map(Hasher::finalize)
+    }
+}
+
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha224(Sha224),
+    Sha256(Sha256),
+    Sha384(Sha384),
+    Sha512(Sha512),
+    Sha3_256(Sha3_256),
+    Sha3_384(Sha3_384),
+    Sha3_512(Sha3_512),
+    Blake2b(Blake2b512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Md5 => Self::Md5(Md5::new()),
+            Algorithm::Sha1 => Self::Sha1(Sha1::new()),
+            Algorithm::Sha224 => Self::Sha224(Sha224::new()),
+            Algorithm::Sha256 => Self::Sha256(Sha256::new()),
+            Algorithm::Sha384 => Self::Sha384(Sha384::new()),
+            Algorithm::Sha512 => Self::Sha512(Sha512::new()),
+            Algorithm::Sha3_256 => Self::Sha3_256(Sha3_256::new()),
+            Algorithm::Sha3_384 => Self::Sha3_384(Sha3_384::new()),
+            Algorithm::Sha3_512 => Self::Sha3_512(Sha3_512::new()),
+            Algorithm::Blake2b => Self::Blake2b(Blake2b512::new()),
+            Algorithm::Blake3 => Self::Blake3(Box::default()),
+        }
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha224(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha384(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Sha3_256(hasher) => hasher.update(data),
+            Self::Sha3_384(hasher) => hasher.update(data),
+            Self::Sha3_512(hasher) => hasher.update(data),
+            Self::Blake2b(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            },
+        }
+    }
+    pub fn finalize(self) -> String {
+        match self {
+            Self::Md5(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha1(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha224(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha256(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha384(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha512(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha3_256(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha3_384(hasher) => hex::encode(hasher.finalize()),
+            Self::Sha3_512(hasher) => hex::encode(hasher.finalize()),
+            Self::Blake2b(hasher) => hex::encode(hasher.finalize()),
+            Self::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+    async fn update_from_file(&mut self, path: &Path, length: u64) -> Result<(), std::io::Error> {
+        let mut file = tokio::fs::File::open(path).await?.take(length);
+        let mut buf = vec![0; 1_048_576];
+        loop {
+            match file.read(&mut buf).await? {
+                0 => return Ok(()),
+                read => self.update(&buf[..read]),
+            }
+        }
+    }
+}
+
+pub fn part_path(path: &str) -> String {
+    path.to_string() + ".part"
+}
+
+// Moves a completed and verified download into place.
+pub fn finalize_download(path: &str) -> Result<(), std::io::Error> {
+    fs::rename(part_path(path), path)
+}
+
+// Removes a download that can't be used, such as one which failed verification.
+fn discard_download(path: &str) {
+    match fs::remove_file(part_path(path)) {
+        Ok(_) => eprintln!("Removed {}.", part_path(path)),
+        Err(e) => eprintln!("Unable to remove {}: {}", part_path(path), e),
     }
-    progress.finish();
-    Ok(vm_path)
 }
 
-pub fn spawn_downloads(url_iso_list: Vec<(String, HeaderMap, String)>, vm_path: &str, distro: &Distro, release: &str, edition: &str, arch: &str) -> Vec<String> {
+pub fn report_partial_download(path: &str) {
+    if std::path::Path::new(&part_path(path)).exists() {
+        eprintln!("The incomplete download has been kept at {}. Run quickget again to resume it.", part_path(path));
+    }
+}
+
+pub fn progress_bar(size: u64) -> ProgressBar {
+    let progress = context().progress.add(ProgressBar::new(size));
+    progress.set_style(ProgressStyle::with_template("[{elapsed}] {bar:40} {eta_precise} {decimal_bytes}/{decimal_total_bytes}  -   {decimal_bytes_per_sec}  {msg}")
+        .unwrap().progress_chars("##-"));
+    progress
+}
+
+pub fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+async fn segmented_download(client: &Client, url: &str, headermap: &HeaderMap, path: &Path, file_size: u64, connections: u64, progress: &ProgressBar) -> Result<(), DownloadError> {
+    let marker = segment_marker(path);
+    tokio::fs::write(&marker, "").await?;
+    tokio::fs::File::create(path).await?.set_len(file_size).await?;
+
+    let segment_size = file_size / connections;
+    let segments = (0..connections).map(|index| {
+        let start = index * segment_size;
+        let end = if index == connections - 1 { file_size - 1 } else { start + segment_size - 1 };
+        let (client, url, headermap, path, progress) = (client.clone(), url.to_string(), headermap.clone(), path.to_path_buf(), progress.clone());
+        tokio::spawn(async move {
+            let (mut written, mut failures) = (0, 0);
+            loop {
+                match download_segment(&client, &url, &headermap, &path, start, end, &progress, &mut written).await {
+                    Err(e) => match retry_delay(&e, &mut failures, &format!("bytes {}-{} of {}", start, end, url)) {
+                        Some(delay) => tokio::time::sleep(delay).await,
+                        None => break Err(e),
+                    },
+                    result => break result,
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for segment in futures::future::join_all(segments).await {
+        segment.map_err(std::io::Error::other)??;
+    }
+    tokio::fs::File::open(path).await?.sync_all().await?;
+    Ok(tokio::fs::remove_file(&marker).await?)
+}
+
+// `written` persists across attempts, so a retried segment continues where the last attempt stopped.
+#[allow(clippy::too_many_arguments)]
+async fn download_segment(client: &Client, url: &str, headermap: &HeaderMap, path: &Path, start: u64, end: u64, progress: &ProgressBar, written: &mut u64) -> Result<(), DownloadError> {
+    let position = start + *written;
+    let request = client.get(url).headers(headermap.clone())
+        .header(RANGE, format!("bytes={}-{}", position, end))
+        .send().await?;
+    check_status(&request)?;
+    if request.status() != StatusCode::PARTIAL_CONTENT || range_start(request.headers()) != Some(position) {
+        return Err(std::io::Error::other(format!("Server did not honour the range request for bytes {}-{}", position, end)).into());
+    }
+
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start(position)).await?;
+
+    let mut stream = request.bytes_stream();
+    while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        *written += chunk.len() as u64;
+        progress.inc(chunk.len() as u64);
+        throttle(chunk.len()).await;
+    }
+    file.flush().await?;
+
+    if *written != end - start + 1 {
+        return Err(DownloadError::Incomplete { url: url.to_string(), expected: end - start + 1, received: *written });
+    }
+    Ok(())
+}
+
+fn segment_marker(path: &Path) -> PathBuf {
+    let mut marker = path.as_os_str().to_owned();
+    marker.push(".segments");
+    PathBuf::from(marker)
+}
+
+fn supports_ranges(headers: &HeaderMap) -> bool {
+    headers.get(ACCEPT_RANGES).is_some_and(|ranges| ranges.as_bytes() == b"bytes")
+}
+
+async fn send_request(client: &Client, url: &str, headermap: &HeaderMap, offset: u64) -> Result<Response, DownloadError> {
+    let mut request = client.get(url).headers(headermap.clone());
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+    }
+    Ok(request.send().await?)
+}
+
+// Content-Range headers take the form "bytes start-end/total", or "bytes */total" for a 416 response.
+fn range_start(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.parse().ok()
+}
+
+fn range_total(headers: &HeaderMap) -> Option<u64> {
+    let range = headers.get(CONTENT_RANGE)?.to_str().ok()?;
+    range.rsplit('/').next()?.parse().ok()
+}
+
+pub fn spawn_downloads(url_iso_list: Vec<(Vec<String>, HeaderMap, String)>, vm_path: &str, distro: &Distro, release: &str, edition: &str, arch: &str) -> Result<Vec<String>, QuickgetError> {
     println!("Downloading images to {}", vm_path);
+    let options = download_options();
+
+    // Refusing unsigned images before they're downloaded needs their checksums up front. Otherwise they're fetched while
+    // the files download. The outer option tells whether the checksum has been fetched yet.
+    let mut checksums = (0..url_iso_list.len()).map(|index| match options.require_signature {
+        true => distro.get_checksum(index, release, edition, arch).map(Some),
+        false => Ok(None),
+    }).collect::<Result<Vec<Option<Option<Hash>>>, QuickgetError>>()?;
+    if options.require_signature {
+        for ((_, _, iso), checksum) in url_iso_list.iter().zip(&checksums) {
+            if !has_signature(digest(checksum)) {
+                return Err(QuickgetError::Verification(format!("{} has no signed checksum, so it won't be downloaded with --require-signature.", iso)));
+            }
+        }
+    }
+
     let mut paths = Vec::new();
-    for (url, headers, iso) in url_iso_list {
+    let mut missing = Vec::new();
+    for (index, (urls, headers, iso)) in url_iso_list.into_iter().enumerate() {
         let path = vm_path.to_string() + iso.as_str();
-        let download = std::thread::spawn(move || {
-            let rt = tokio::runtime::Runtime::new().unwrap();
-            rt.block_on(async {
-                handle_download(url, path, headers).await
-            })
-        });
-        let checksum = match distro.has_checksum(paths.len()) {
-            true => distro.get_checksum(release, edition, arch).unwrap_or("".to_string()),
-            _ => "".to_string(),
-        };
-
-        let path = match download.join().expect("ERROR: Download thread panicked") {
-            Ok(result) => result,
-            Err(e) => {
-                eprintln!("ERROR: {}", e);
-                std::process::exit(1);
+        // Until its checksum has been fetched, an image can only be found by its URLs. An image stored by URL may have
+        // changed upstream since, so --refresh ignores those.
+        let checksum = checksums[index].clone().flatten();
+        let key = Some(store_key(checksum.as_ref(), &urls))
+            .filter(|key| options.image_store && !(options.refresh && key.starts_with("url-")));
+        let image = key.and_then(|key| stored_image(&key, checksum.as_ref()).map(|image| (key, image)));
+        match image.map(|(key, image)| place_image(&image, &key, &path)) {
+            Some(Ok(method)) => println!("Using {} from the image store ({}).", iso, method),
+            Some(Err(e)) => {
+                eprintln!("WARNING: Unable to use {} from the image store: {}", iso, e);
+                missing.push((index, (urls, headers, iso)));
             },
-        };
+            None => missing.push((index, (urls, headers, iso))),
+        }
+        paths.push(path);
+    }
+
+    check_free_space(&missing.iter().map(|(_, file)| file.clone()).collect::<Vec<_>>(), vm_path)?;
+    let mut downloads = Vec::new();
+    let mut senders = Vec::new();
+    for (index, (urls, headers, _)) in &missing {
+        let (sender, receiver) = oneshot::channel();
+        downloads.push(context().runtime.spawn(handle_download(urls.clone(), paths[*index].clone(), headers.clone(), Some(receiver))));
+        senders.push(sender);
+    }
+    let abort_handles = downloads.iter().map(|download| download.abort_handle()).collect::<Vec<_>>();
 
-        if checksum.len() > 0 {
-            println!("Verifying image with checksum {}", &checksum);
-            match verify_image(&path, checksum) {
+    let mut stored = Vec::new();
+    for (position, ((index, (urls, _, iso)), sender)) in missing.iter().zip(senders).enumerate() {
+        if checksums[*index].is_none() {
+            match distro.get_checksum(*index, release, edition, arch) {
+                Ok(checksum) => checksums[*index] = Some(checksum),
+                Err(e) => {
+                    abort_handles.iter().for_each(|download| download.abort());
+                    missing.iter().for_each(|(index, _)| report_partial_download(&paths[*index]));
+                    return Err(e);
+                },
+            }
+            // With the checksum known, the image may be in the store after all. The download is only stopped once the
+            // stored copy is in place, since it writes to a separate partial file.
+            let image = checksums[*index].clone().flatten().filter(|_| options.image_store).and_then(|checksum| {
+                let key = store_key(Some(&checksum), urls);
+                stored_image(&key, Some(&checksum)).map(|image| (key, image))
+            });
+            match image.map(|(key, image)| place_image(&image, &key, &paths[*index])) {
+                Some(Ok(method)) => {
+                    abort_handles[position].abort();
+                    context().runtime.block_on(&mut downloads[position]).ok();
+                    let path = part_path(&paths[*index]);
+                    fs::remove_file(&path).ok();
+                    fs::remove_file(segment_marker(Path::new(&path))).ok();
+                    println!("Using {} from the image store ({}).", iso, method);
+                    stored.push(*index);
+                    continue;
+                },
+                Some(Err(e)) => eprintln!("WARNING: Unable to use {} from the image store: {}", iso, e),
+                None => (),
+            }
+        }
+        if let Some(checksum) = checksums[*index].clone().expect("The checksum was just fetched") {
+            sender.send(checksum).ok();
+        }
+    }
+    let downloads = downloads.into_iter().zip(&missing)
+        .filter(|(_, (index, _))| !stored.contains(index))
+        .map(|(download, _)| download)
+        .collect::<Vec<_>>();
+    missing.retain(|(index, _)| !stored.contains(index));
+
+    // Stop at the first failure. The other downloads are cancelled, and whatever they've written is kept to be resumed.
+    let downloads = futures::future::try_join_all(downloads.into_iter().map(|download| async {
+        download.await.expect("ERROR: Download task panicked")
+    }));
+    let digests = match context().runtime.block_on(downloads) {
+        Ok(results) => results.into_iter().map(|(_, digest)| digest).collect::<Vec<_>>(),
+        Err(e) => {
+            abort_handles.iter().for_each(|download| download.abort());
+            missing.iter().for_each(|(index, _)| report_partial_download(&paths[*index]));
+            return Err(e.into());
+        },
+    };
+
+    // Throws away a downloaded file which doesn't match what was expected, keeping the others to be resumed.
+    let reject = |index: usize, message: String| {
+        discard_download(&paths[index]);
+        missing.iter().filter(|(other, _)| *other != index).for_each(|(other, _)| report_partial_download(&paths[*other]));
+        QuickgetError::Verification(message)
+    };
+    let pinned = pinned_files(&distro.name, arch, release, edition);
+    for ((index, _), digest) in missing.iter().zip(digests) {
+        let path = &paths[*index];
+        // For files pinned without a checksum, the size is all that ties a download to the lockfile.
+        if let Some(size) = pinned.and_then(|files| files.get(*index)?.size) {
+            let length = fs::metadata(part_path(path)).map_err(|e| QuickgetError::Io(format!("Unable to read {}: {}", part_path(path), e)))?.len();
+            if length != size {
+                return Err(reject(*index, format!("{} is {} bytes, but the lockfile pins it at {} bytes.", file_name(path), length, size)));
+            }
+        }
+        if let Some(checksum) = checksums[*index].clone().flatten() {
+            println!("Verifying {} with {} checksum {}", file_name(path), checksum.0, checksum.1);
+            // If the checksum wasn't available while the image was streaming, fall back to hashing the file.
+            let verified = match digest {
+                Some(digest) => Ok(digest.eq_ignore_ascii_case(&checksum.1)),
+                None => verify_image(&part_path(path), &checksum),
+            };
+            match verified {
                 Ok(true) => println!("Successfully verified image."),
-                Ok(false) => {
-                    eprintln!("ERROR! Image verification failed.");
-                    std::process::exit(1);
+                Ok(false) => return Err(reject(*index, format!("{} does not match the expected checksum.", file_name(path)))),
+                // An image which couldn't be hashed is kept to be checked again, but is never used unverified.
+                Err(e) => {
+                    missing.iter().for_each(|(index, _)| report_partial_download(&paths[*index]));
+                    return Err(e.context(&format!("Unable to verify {}", file_name(path))));
                 },
-                Err(e) => eprintln!("WARNING! {}", e),
             }
         }
-        paths.push(path);
     }
-    paths
+
+    // Images from the store are already in place, while downloads are still partial files.
+    let is_downloaded = |index: usize| missing.iter().any(|(other, _)| *other == index);
+    let unverified_paths = paths.iter().enumerate()
+        .map(|(index, path)| if is_downloaded(index) { part_path(path) } else { path.clone() })
+        .collect::<Vec<_>>();
+    match distro.verify_after(&unverified_paths, release, edition, arch) {
+        Some(Ok(())) => println!("Successfully verified {} image.", distro.pretty_name),
+        Some(Err(e)) => {
+            unverified_paths.iter().for_each(|path| match fs::remove_file(path) {
+                Ok(_) => eprintln!("Removed {}.", path),
+                Err(e) => eprintln!("Unable to remove {}: {}", path, e),
+            });
+            return Err(e.context(&format!("Failed to verify {} image", distro.pretty_name)));
+        },
+        None => (),
+    };
+
+    for (index, path) in unverified_paths.iter().enumerate() {
+        let signed = signature::check_image(path, digest(&checksums[index]));
+        let error = match signed {
+            Ok(Some(signer)) => {
+                println!("The checksum of {} is signed by {}.", file_name(&paths[index]), signer);
+                continue;
+            },
+            Ok(None) if !options.require_signature => continue,
+            Ok(None) => QuickgetError::Verification(format!("{} isn't signed by a trusted key.", file_name(&paths[index]))),
+            Err(e) => e,
+        };
+        unverified_paths.iter().for_each(|path| match fs::remove_file(path) {
+            Ok(_) => eprintln!("Removed {}.", path),
+            Err(e) => eprintln!("Unable to remove {}: {}", path, e),
+        });
+        return Err(error);
+    }
+
+    for (index, _) in &missing {
+        finalize_download(&paths[*index]).map_err(|e| QuickgetError::Io(format!("Unable to move {} into place: {}", part_path(&paths[*index]), e)))?;
+    }
+    if options.image_store {
+        for (index, (urls, _, _)) in &missing {
+            add_image(&paths[*index], &store_key(checksums[*index].clone().flatten().as_ref(), urls));
+        }
+    }
+    Ok(paths)
 }
 
-pub fn test_urls(url_iso_list: Vec<(String, HeaderMap, String)>) {
-    url_iso_list.into_iter().for_each(|(url, headers, _)| {
-        let client = reqwest::blocking::Client::new();
-        let request = client.get(&url).headers(headers).send().unwrap_or_else(|e| {
-            eprintln!("Error while testing URL {}: {}", url, e);
-            std::process::exit(1);
+// Refuses to start if the images can't fit in the VM directory, and warns if they'd leave no room for the VM's disk.
+fn check_free_space(url_iso_list: &[(Vec<String>, HeaderMap, String)], vm_path: &str) -> Result<(), QuickgetError> {
+    let directory = if vm_path.is_empty() { "." } else { vm_path };
+    let Some(available) = available_space(Path::new(directory)) else {
+        return Ok(());
+    };
+
+    let sizes = context().runtime.block_on(futures::future::join_all(url_iso_list.iter().map(|(urls, headers, _)| remote_size(urls, headers))));
+    let mut unknown = Vec::new();
+    let mut required = 0;
+    for ((_, _, iso), size) in url_iso_list.iter().zip(sizes) {
+        // Partial downloads only need room for what's left, unless they were segmented, which are started again.
+        let part = part_path(&(vm_path.to_string() + iso));
+        let existing = match segment_marker(Path::new(&part)).exists() {
+            true => 0,
+            false => fs::metadata(&part).map(|metadata| metadata.len()).unwrap_or(0),
+        };
+        match size {
+            Some(size) => required += size.saturating_sub(existing),
+            None => unknown.push(iso.as_str()),
+        }
+    }
+
+    let mib = |bytes: u64| bytes as f64 / 1_048_576.0;
+    if required > available {
+        return Err(QuickgetError::Io(format!("The download needs {:.2} MiB, but only {:.2} MiB is available in {}.", mib(required), mib(available), directory)));
+    }
+    if required + DISK_IMAGE_MARGIN > available {
+        eprintln!("WARNING: Only {:.2} MiB will be left in {} after downloading, which may not be enough for the VM's disk.", mib(available - required), directory);
+    }
+    if !unknown.is_empty() {
+        eprintln!("WARNING: Unable to determine the size of {}. There may not be enough space to download it.", unknown.join(", "));
+    }
+    Ok(())
+}
+
+pub async fn remote_size(urls: &[String], headers: &HeaderMap) -> Option<u64> {
+    if download_options().offline {
+        return None;
+    }
+    for url in urls {
+        match context().client.head(url).headers(headers.clone()).timeout(Duration::from_secs(10)).send().await {
+            // content_length() reports the size of the (empty) body of a HEAD response, so read the header instead.
+            Ok(response) if response.status().is_success() => {
+                return response.headers().get(CONTENT_LENGTH)?.to_str().ok()?.parse().ok().filter(|&size| size > 0);
+            },
+            _ => continue,
+        }
+    }
+    None
+}
+
+#[cfg(unix)]
+fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // Safety: `path` is a valid C string, and statvfs only writes to `stats`, which is read after it succeeds.
+    match unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } {
+        0 => {
+            let stats = unsafe { stats.assume_init() };
+            // The field types differ between platforms.
+            #[allow(clippy::unnecessary_cast)]
+            Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+        },
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn available_space(_: &Path) -> Option<u64> {
+    None
+}
+
+pub fn test_urls(url_iso_list: Vec<(Vec<String>, HeaderMap, String)>) -> Result<(), QuickgetError> {
+    url_iso_list.into_iter().try_for_each(|(urls, headers, iso)| {
+        let client = &context().blocking_client;
+        let available = urls.iter().any(|url| {
+            match client.get(url).headers(headers.clone()).send() {
+                Ok(request) if request.status().is_success() => {
+                    println!("Got response from {}. File exists. Size: {:.2} MiB", url, request.content_length().unwrap_or(0) as f64 / 1_048_576.0);
+                    true
+                },
+                Ok(request) => {
+                    eprintln!("Error while testing URL {}: Server responded with {}", url, request.status());
+                    false
+                },
+                Err(e) => {
+                    eprintln!("Error while testing URL {}: {}", url, e);
+                    false
+                },
+            }
         });
-        println!("Got response from {}. File exists. Size: {:.2} MiB", url, request.content_length().unwrap_or(0) as f64 / 1_048_576.0);
+        match available {
+            true => Ok(()),
+            false => Err(QuickgetError::Network(format!("{} is not available from any mirror.", iso))),
+        }
     })
 }
 
-pub fn verify_image(filepath: &str, checksum: String) -> Result<bool, String> {
-    let hash = match checksum.len() {
-        32 => {
-            let bytes = fs::read(&filepath).map_err(|_| format!("Unable to find MD5sum for file {}", filepath))?;
-            hex::encode(Md5::digest(bytes))
-        },
-        40 => {
-            let bytes = fs::read(&filepath).map_err(|_| format!("Unable to find SHA1sum for file {}", filepath))?;
-            hex::encode(Sha1::digest(bytes))
-        },
-        64 => {
-            let bytes = fs::read(&filepath).map_err(|_| format!("Unable to find SHA256sum for file {}", filepath))?;
-            hex::encode(Sha256::digest(bytes))
+// Finds an image in the store. A VM may have written to a stored image through a hard link, so one with a checksum is
+// hashed again before it's used, and dropped from the store if it no longer matches.
+fn stored_image(key: &str, checksum: Option<&Hash>) -> Option<PathBuf> {
+    let image = find_image(key)?;
+    let Some(checksum) = checksum else {
+        return Some(image);
+    };
+    match verify_image(&image.to_string_lossy(), checksum) {
+        Ok(true) => Some(image),
+        Ok(false) => {
+            eprintln!("WARNING: {} in the image store no longer matches its checksum. It will be downloaded again.", image.display());
+            if let Err(e) = remove_image(key) {
+                eprintln!("WARNING: Unable to remove {} from the image store: {}", image.display(), e);
+            }
+            None
         },
-        128 => {
-            let bytes = fs::read(&filepath).map_err(|_| format!("Unable to find SHA512sum for file {}", filepath))?;
-            hex::encode(Sha512::digest(bytes))
+        Err(e) => {
+            eprintln!("WARNING: Unable to check {} in the image store: {}", image.display(), e);
+            None
         },
-        _ => return Err(format!("Can't guess hash algorithm, not checking {} hash.", filepath)),
-    };
-    Ok(hash == checksum)
+    }
+}
+
+// The digest of a checksum which has been fetched, or "" if there isn't one.
+fn digest(checksum: &Option<Option<Hash>>) -> &str {
+    checksum.as_ref().and_then(Option::as_ref).map_or("", |(_, digest)| digest.as_str())
+}
+
+pub fn verify_image(filepath: &str, (algorithm, checksum): &Hash) -> Result<bool, QuickgetError> {
+    let mut hasher = Hasher::new(*algorithm);
+    let mut file = File::open(filepath).map_err(|e| QuickgetError::Io(format!("Unable to open {}: {}", filepath, e)))?;
+    let size = file.metadata().map_err(|e| QuickgetError::Io(format!("Unable to read metadata of {}: {}", filepath, e)))?.len();
+    let progress = progress_bar(size).with_message(file_name(filepath));
+
+    if download_options().mmap {
+        // Safety: the image isn't expected to be modified while it's being verified.
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| QuickgetError::Io(format!("Unable to map {} into memory: {}", filepath, e)))?;
+        for block in map.chunks(VERIFY_BLOCK_SIZE) {
+            hasher.update(block);
+            progress.inc(block.len() as u64);
+        }
+    } else {
+        let mut buf = vec![0; VERIFY_BLOCK_SIZE];
+        loop {
+            match file.read(&mut buf).map_err(|e| QuickgetError::Io(format!("Unable to read {}: {}", filepath, e)))? {
+                0 => break,
+                read => {
+                    hasher.update(&buf[..read]);
+                    progress.inc(read as u64);
+                },
+            }
+        }
+    }
+    progress.finish();
+    // Some vendors publish their checksums in uppercase.
+    Ok(hasher.finalize().eq_ignore_ascii_case(checksum))
 }
 
-pub fn create_config(vm_path: &str, paths: Vec<String>, distro: &Distro, release: &str, edition: &str) -> Result<String, Box<dyn Error>> {
+pub fn create_config(vm_path: &str, paths: Vec<String>, distro: &Distro, release: &str, edition: &str) -> Result<String, QuickgetError> {
     let config_path = vm_path.replace("/", ".conf");
     let path = std::path::Path::new(&config_path);
 
@@ -149,12 +984,12 @@ arch="{}"
 
     match distro.config {
         Config::Overwrite(get_config) => {
-            let config = get_config(paths, release, edition, &distro.arch)?;
+            let config = get_config(paths, release, edition, &distro.arch).map_err(|e| QuickgetError::from(e).context("Unable to create the config"))?;
             fs::write(&path, quickemu_path + &config)?;
         },
         Config::Addition(get_addition) => {
             let default = default_config(&distro);
-            let addition = get_addition(paths, release, edition, &distro.arch);
+            let addition = get_addition(paths, release, edition, &distro.arch).map_err(|e| QuickgetError::from(e).context("Unable to create the config"))?;
             fs::write(&path, default + &addition)?;
         },
         _ => {
@@ -162,6 +997,6 @@ arch="{}"
         },
     }
 
-    Ok(path.to_str().unwrap().to_string())
+    Ok(config_path)
 }
 
@@ -0,0 +1,634 @@
+// Checks the OpenPGP signatures that distros publish for their checksums, so that a checksum doesn't have to be trusted
+// just because of where it was downloaded from. Only what's needed for that is implemented: version 4 RSA keys and
+// signatures (RFC 4880), binary or ASCII armoured, and cleartext signed files.
+use crate::error::QuickgetError;
+use crate::quickget::download_options;
+use crate::utils::{cache_dir, collect_page, context};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use itertools::Itertools;
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use rsa::traits::PublicKeyParts;
+use sha1::Sha1;
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+use sha2::digest::DynDigest;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Keys are built in, and pinned by the fingerprint of their primary key. --refresh-keys fetches newer copies, which may
+// have new subkeys or expiry dates, into the cache directory. Neither the keyserver nor the cache needs to be trusted,
+// as a key is only used if its fingerprint matches.
+pub static UBUNTU_KEYS: Keys = Keys {
+    name: "ubuntu",
+    bundled: include_str!("../keys/ubuntu.asc"),
+    fingerprints: &["843938DF228D22F7B3742BC0D94AA3F0EFE21092"],
+    url: None,
+};
+pub static KDE_NEON_KEYS: Keys = Keys {
+    name: "kdeneon",
+    bundled: include_str!("../keys/kdeneon.asc"),
+    fingerprints: &["348C8651206633FD983A8FC4DEACEA00075E1D76"],
+    url: None,
+};
+// Fedora signs each release with a new key, so a release is only trusted if it's signed with its own.
+static FEDORA_KEYS: [(&str, Keys); 5] = [
+    ("38", fedora_keys(&["6A51BBABBA3D5467B6171221809A8D7CEB10B464"])),
+    ("39", fedora_keys(&["E8F23996F23218640CB44CBE75CF5AC418B8E74C"])),
+    ("40", fedora_keys(&["115DF9AEF857853EE8445D0A0727707EA15B79CC"])),
+    ("41", fedora_keys(&["466CF2D8B60BC3057AA9453ED0622462E99D6AD1"])),
+    ("42", fedora_keys(&["B0F4950458F69E1150C6C5EDC8AC4916105EF944"])),
+];
+
+const KEYSERVER: &str = "https://keyserver.ubuntu.com/pks/lookup?op=get&options=mr&search=0x";
+
+pub struct Keys {
+    // Refreshed keys are cached under this name.
+    name: &'static str,
+    bundled: &'static str,
+    fingerprints: &'static [&'static str],
+    // Where --refresh-keys fetches the keys from. Without one, they're fetched from the keyserver by fingerprint.
+    url: Option<&'static str>,
+}
+
+const fn fedora_keys(fingerprints: &'static [&'static str]) -> Keys {
+    Keys {
+        name: "fedora",
+        bundled: include_str!("../keys/fedora.asc"),
+        fingerprints,
+        url: Some("https://fedoraproject.org/fedora.gpg"),
+    }
+}
+
+pub fn fedora_release_keys(release: &str) -> Option<&'static Keys> {
+    FEDORA_KEYS.iter().find(|(version, _)| *version == release).map(|(_, keys)| keys)
+}
+
+struct PublicKey {
+    // Signatures made by subkeys are reported as coming from their primary key.
+    owner: String,
+    key_id: [u8; 8],
+    rsa: RsaPublicKey,
+}
+
+struct Signature<'a> {
+    kind: u8,
+    hash_algorithm: u8,
+    // The part of the packet covered by the signature.
+    hashed: &'a [u8],
+    issuer: Option<[u8; 8]>,
+    key_flags: Option<u8>,
+    left16: &'a [u8],
+    value: &'a [u8],
+}
+
+enum Status {
+    Signed(String),
+    // Some distros sign their images rather than their checksums, so the image has to be downloaded first.
+    Pending(Vec<u8>, &'static Keys),
+}
+
+// Checksums whose signatures have been checked, or which are waiting for their image to be checked.
+static SIGNATURES: Mutex<Vec<(String, Status)>> = Mutex::new(vec![]);
+
+pub fn record_signed(checksum: &str, signer: &str) {
+    SIGNATURES.lock().unwrap().push((checksum.to_lowercase(), Status::Signed(signer.to_string())));
+}
+
+// The keys are loaded first, so that --require-signature can refuse an image before it's downloaded if they're missing.
+pub fn defer_image_signature(checksum: &str, signature: Vec<u8>, keys: &'static Keys) {
+    match load_keys(keys) {
+        Ok(loaded) if !loaded.is_empty() => SIGNATURES.lock().unwrap().push((checksum.to_lowercase(), Status::Pending(signature, keys))),
+        Ok(_) => eprintln!("WARNING: None of the {} signing keys are available, so the image's signature can't be checked.", keys.name),
+        Err(e) => eprintln!("WARNING: Unable to load the {} signing keys: {}", keys.name, e),
+    }
+}
+
+// The key which signed a checksum, if its signature has already been checked.
+pub fn signer(checksum: &str) -> Option<String> {
+    SIGNATURES.lock().unwrap().iter().find_map(|(signed, status)| match status {
+        Status::Signed(signer) if signed.eq_ignore_ascii_case(checksum) => Some(signer.clone()),
+        _ => None,
+    })
+}
+
+// Used by --require-signature to refuse images before they're downloaded, where possible.
+pub fn has_signature(checksum: &str) -> bool {
+    !checksum.is_empty() && SIGNATURES.lock().unwrap().iter().any(|(signed, _)| signed.eq_ignore_ascii_case(checksum))
+}
+
+// Returns the key which signed an image that has already been verified against `checksum`, or None if it isn't signed.
+pub fn check_image(path: &str, checksum: &str) -> Result<Option<String>, QuickgetError> {
+    let pending = {
+        let signatures = SIGNATURES.lock().unwrap();
+        match signatures.iter().find(|(signed, _)| !checksum.is_empty() && signed.eq_ignore_ascii_case(checksum)) {
+            Some((_, Status::Signed(signer))) => return Ok(Some(signer.clone())),
+            Some((_, Status::Pending(signature, keys))) => (signature.clone(), *keys),
+            None => return Ok(None),
+        }
+    };
+    let (signature, keys) = pending;
+    let result = load_keys(keys).and_then(|keys| verify(&signature, &keys, |hasher, _| {
+        let mut file = File::open(path).map_err(|e| QuickgetError::Io(format!("Unable to open {}: {}", path, e)))?;
+        let mut buf = vec![0; 1_048_576];
+        loop {
+            match file.read(&mut buf).map_err(|e| QuickgetError::Io(format!("Unable to read {}: {}", path, e)))? {
+                0 => return Ok(()),
+                read => hasher.update(&buf[..read]),
+            }
+        }
+    }));
+    let signer = settle(result, path)?;
+    if let Some(signer) = &signer {
+        record_signed(checksum, signer);
+    }
+    Ok(signer)
+}
+
+// Checks a checksum file with a detached signature, and records `checksum` as signed if it is.
+// A bad signature is an error, while one which can't be checked leaves the checksum unsigned.
+pub fn check_checksum_file(checksum: &str, contents: &str, signature: &str, keys: &'static Keys, description: &str) -> Result<(), QuickgetError> {
+    let result = load_keys(keys).and_then(|keys| verify(signature.as_bytes(), &keys, |hasher, kind| {
+        match kind {
+            0x01 => hasher.update(canonical_text(contents).as_bytes()),
+            _ => hasher.update(contents.as_bytes()),
+        }
+        Ok(())
+    }));
+    if let Some(signer) = settle(result, description)? {
+        record_signed(checksum, &signer);
+    }
+    Ok(())
+}
+
+// Checks a cleartext signed file. Returns its text and the key which signed it, or None if the signature can't be checked.
+pub fn check_cleartext_file(document: &str, keys: &'static Keys, description: &str) -> Result<Option<(String, String)>, QuickgetError> {
+    let mut lines = document.lines().skip_while(|line| line.trim_end() != "-----BEGIN PGP SIGNED MESSAGE-----").skip(1);
+    // Skip the armour headers, which name the hash algorithm.
+    lines.by_ref().take_while(|line| !line.trim().is_empty()).for_each(drop);
+    let text = lines.by_ref()
+        .take_while(|line| line.trim_end() != "-----BEGIN PGP SIGNATURE-----")
+        .map(|line| line.strip_prefix("- ").unwrap_or(line))
+        .collect::<Vec<&str>>();
+    let signature = "-----BEGIN PGP SIGNATURE-----\n".to_string() + &lines.join("\n");
+    if text.is_empty() {
+        return settle(Err(QuickgetError::Parse("It isn't a cleartext signed file.".into())), description).map(|_| None);
+    }
+
+    // Trailing whitespace isn't signed, and lines are hashed with CRLF endings.
+    let signed = text.iter().map(|line| line.trim_end_matches([' ', '\t'])).join("\r\n");
+    let result = load_keys(keys).and_then(|keys| verify(signature.as_bytes(), &keys, |hasher, _| {
+        hasher.update(signed.as_bytes());
+        Ok(())
+    }));
+    Ok(settle(result, description)?.map(|signer| (text.join("\n"), signer)))
+}
+
+// Some signatures are only published in binary form, which `collect_page` can't return.
+pub fn fetch_signature(url: &str) -> Result<Vec<u8>, QuickgetError> {
+    if download_options().offline {
+        return Err(QuickgetError::Network(format!("{} can't be fetched in offline mode.", url)));
+    }
+    let response = context().blocking_client.get(url).send()?.error_for_status()?;
+    Ok(response.bytes()?.to_vec())
+}
+
+fn settle(result: Result<Option<String>, QuickgetError>, description: &str) -> Result<Option<String>, QuickgetError> {
+    match result {
+        Ok(None) => {
+            eprintln!("WARNING: {} isn't signed by a trusted key.", description);
+            Ok(None)
+        },
+        Err(QuickgetError::Verification(e)) => Err(QuickgetError::Verification(format!("Bad signature on {}: {}", description, e))),
+        Err(e) => {
+            eprintln!("WARNING: Unable to check the signature of {}: {}", description, e);
+            Ok(None)
+        },
+        signer => signer,
+    }
+}
+
+// Keys given with --keyring replace the built-in ones, so that they can be pinned locally.
+fn load_keys(keys: &Keys) -> Result<Vec<PublicKey>, QuickgetError> {
+    let keyrings = &download_options().keyrings;
+    if !keyrings.is_empty() {
+        return keyrings.iter().map(|path| {
+            let data = fs::read(path).map_err(|e| QuickgetError::Io(format!("Unable to read keyring {}: {}", path, e)))?;
+            parse_keyring(&data)
+        }).flatten_ok().collect();
+    }
+    if download_options().refresh_keys {
+        if let Err(e) = refresh_keys(keys) {
+            eprintln!("WARNING: Unable to refresh the {} signing keys: {}", keys.name, e);
+        }
+    }
+    let mut found = parse_keyring(keys.bundled.as_bytes())?;
+    if let Some(cached) = key_cache_path(keys.name).and_then(|path| fs::read(path).ok()) {
+        found.extend(parse_keyring(&cached)?);
+    }
+    Ok(found.into_iter().filter(|key| keys.fingerprints.contains(&key.owner.as_str())).collect())
+}
+
+fn key_cache_path(name: &str) -> Option<PathBuf> {
+    Some(cache_dir()?.join("keys").join(name.to_string() + ".asc"))
+}
+
+// Keyrings which have already been refreshed during this run.
+static REFRESHED: Mutex<Vec<&str>> = Mutex::new(vec![]);
+
+fn refresh_keys(keys: &Keys) -> Result<(), QuickgetError> {
+    if REFRESHED.lock().unwrap().contains(&keys.name) {
+        return Ok(());
+    }
+    let keyring = match keys.url {
+        Some(url) => collect_page(url.to_string())?,
+        None => keys.fingerprints.iter()
+            .map(|fingerprint| collect_page(KEYSERVER.to_string() + fingerprint))
+            .collect::<Result<Vec<String>, _>>()?
+            .join("\n"),
+    };
+    // A response without any pinned keys would be of no use, so it doesn't replace what's cached.
+    if !parse_keyring(keyring.as_bytes())?.iter().any(|key| keys.fingerprints.contains(&key.owner.as_str())) {
+        return Err(QuickgetError::Network("None of the pinned keys were returned.".into()));
+    }
+    let path = key_cache_path(keys.name).ok_or(QuickgetError::Io("Unable to find the cache directory.".into()))?;
+    fs::create_dir_all(path.parent().expect("The key cache is inside a directory"))
+        .and_then(|_| fs::write(&path, keyring))
+        .map_err(|e| QuickgetError::Io(format!("Unable to write {}: {}", path.display(), e)))?;
+    REFRESHED.lock().unwrap().push(keys.name);
+    Ok(())
+}
+
+// Reads the RSA keys in a keyring. Subkeys are only used if they're bound to their primary key by a valid signature.
+fn parse_keyring(data: &[u8]) -> Result<Vec<PublicKey>, QuickgetError> {
+    let data = dearmor(data)?;
+    let mut keys = Vec::new();
+    // The primary key's packet and its position in `keys`, followed by the subkey waiting for its binding signature.
+    let mut primary: Option<(&[u8], usize)> = None;
+    let mut subkey: Option<(&[u8], RsaPublicKey)> = None;
+    for (tag, body) in packets(&data)? {
+        match tag {
+            6 => {
+                subkey = None;
+                primary = rsa_key(body).map(|rsa| {
+                    let fingerprint = fingerprint(body);
+                    keys.push(PublicKey { owner: hex::encode_upper(fingerprint), key_id: key_id(&fingerprint), rsa });
+                    (body, keys.len() - 1)
+                });
+            },
+            14 => subkey = primary.and_then(|_| rsa_key(body)).map(|rsa| (body, rsa)),
+            2 => {
+                let (Some((primary_body, index)), Some((subkey_body, rsa))) = (primary, &subkey) else {
+                    continue;
+                };
+                let Some(signature) = parse_signature(body).filter(|signature| signature.kind == 0x18) else {
+                    continue;
+                };
+                // Subkeys which can't sign are of no use here.
+                if signature.key_flags.is_some_and(|flags| flags & 0x02 == 0) {
+                    continue;
+                }
+                let Some((mut hasher, scheme)) = hasher(signature.hash_algorithm) else {
+                    continue;
+                };
+                hash_key(&mut *hasher, primary_body);
+                hash_key(&mut *hasher, subkey_body);
+                if check(&signature, hasher, scheme, &keys[index].rsa) {
+                    let owner = keys[index].owner.clone();
+                    keys.push(PublicKey { owner, key_id: key_id(&fingerprint(subkey_body)), rsa: rsa.clone() });
+                    subkey = None;
+                }
+            },
+            _ => (),
+        }
+    }
+    Ok(keys)
+}
+
+// Returns the key which made one of the signatures, or None if none of them were made by `keys`.
+// `hash_data` feeds the signed data to the hasher, given the type of signature.
+fn verify(signature: &[u8], keys: &[PublicKey], hash_data: impl Fn(&mut dyn DynDigest, u8) -> Result<(), QuickgetError>) -> Result<Option<String>, QuickgetError> {
+    let data = dearmor(signature)?;
+    let mut attempted = false;
+    for (_, body) in packets(&data)?.into_iter().filter(|(tag, _)| *tag == 2) {
+        // Only signatures of binary documents and text are relevant.
+        let Some(signature) = parse_signature(body).filter(|signature| signature.kind <= 0x01) else {
+            continue;
+        };
+        for key in keys.iter().filter(|key| signature.issuer == Some(key.key_id)) {
+            let Some((mut hasher, scheme)) = hasher(signature.hash_algorithm) else {
+                continue;
+            };
+            attempted = true;
+            hash_data(&mut *hasher, signature.kind)?;
+            if check(&signature, hasher, scheme, &key.rsa) {
+                return Ok(Some(key.owner.clone()));
+            }
+        }
+    }
+    match attempted {
+        true => Err(QuickgetError::Verification("The signature doesn't match the signed data.".into())),
+        false => Ok(None),
+    }
+}
+
+fn check(signature: &Signature, mut hasher: Box<dyn DynDigest>, scheme: Pkcs1v15Sign, key: &RsaPublicKey) -> bool {
+    hasher.update(signature.hashed);
+    hasher.update(&[0x04, 0xff]);
+    hasher.update(&(signature.hashed.len() as u32).to_be_bytes());
+    let digest = hasher.finalize();
+    if digest[..2] != *signature.left16 || signature.value.len() > key.size() {
+        return false;
+    }
+    // Leading zeros are dropped from the signature, but RSA expects it to be as long as the modulus.
+    let mut value = vec![0; key.size() - signature.value.len()];
+    value.extend_from_slice(signature.value);
+    key.verify(scheme, &digest, &value).is_ok()
+}
+
+fn hasher(algorithm: u8) -> Option<(Box<dyn DynDigest>, Pkcs1v15Sign)> {
+    Some(match algorithm {
+        8 => (Box::new(Sha256::new()), Pkcs1v15Sign::new::<Sha256>()),
+        9 => (Box::new(Sha384::new()), Pkcs1v15Sign::new::<Sha384>()),
+        10 => (Box::new(Sha512::new()), Pkcs1v15Sign::new::<Sha512>()),
+        11 => (Box::new(Sha224::new()), Pkcs1v15Sign::new::<Sha224>()),
+        // SHA-1 and older algorithms are too weak to rely on.
+        _ => return None,
+    })
+}
+
+fn hash_key(hasher: &mut dyn DynDigest, body: &[u8]) {
+    hasher.update(&[0x99]);
+    hasher.update(&(body.len() as u16).to_be_bytes());
+    hasher.update(body);
+}
+
+fn fingerprint(body: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hash_key(&mut hasher, body);
+    Digest::finalize(hasher).into()
+}
+
+fn key_id(fingerprint: &[u8; 20]) -> [u8; 8] {
+    fingerprint[12..].try_into().expect("A fingerprint is 20 bytes long")
+}
+
+fn rsa_key(body: &[u8]) -> Option<RsaPublicKey> {
+    let (header, rest) = split(body, 6)?;
+    if header[0] != 4 || !matches!(header[5], 1..=3) {
+        return None;
+    }
+    let (n, rest) = mpi(rest)?;
+    let (e, _) = mpi(rest)?;
+    RsaPublicKey::new_with_max_size(BigUint::from_bytes_be(n), BigUint::from_bytes_be(e), 16384).ok()
+}
+
+fn parse_signature(body: &[u8]) -> Option<Signature<'_>> {
+    let (header, rest) = split(body, 6)?;
+    if header[0] != 4 || !matches!(header[2], 1..=3) {
+        return None;
+    }
+    let hashed_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let (hashed_subpackets, rest) = split(rest, hashed_length)?;
+    let (length, rest) = split(rest, 2)?;
+    let (unhashed_subpackets, rest) = split(rest, u16::from_be_bytes([length[0], length[1]]) as usize)?;
+    let (left16, rest) = split(rest, 2)?;
+    let (value, _) = mpi(rest)?;
+
+    let hashed = subpackets(hashed_subpackets)?;
+    let unhashed = subpackets(unhashed_subpackets)?;
+    let issuer = hashed.iter().chain(&unhashed).find_map(|(kind, data)| match (kind, data.len()) {
+        (16, 8) => data[..].try_into().ok(),
+        // An issuer fingerprint, which ends with the key ID.
+        (33, 21) if data[0] == 4 => data[13..].try_into().ok(),
+        _ => None,
+    });
+    let key_flags = hashed.iter().find(|(kind, data)| *kind == 27 && !data.is_empty()).map(|(_, data)| data[0]);
+
+    Some(Signature { kind: header[1], hash_algorithm: header[3], hashed: &body[..6 + hashed_length], issuer, key_flags, left16, value })
+}
+
+fn subpackets(mut data: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut subpackets = Vec::new();
+    while !data.is_empty() {
+        let (length, rest) = match data {
+            [first @ 0..=191, rest @ ..] => (*first as usize, rest),
+            [first @ 192..=254, second, rest @ ..] => (((*first as usize - 192) << 8) + *second as usize + 192, rest),
+            [255, a, b, c, d, rest @ ..] => (u32::from_be_bytes([*a, *b, *c, *d]) as usize, rest),
+            _ => return None,
+        };
+        let (subpacket, rest) = split(rest, length)?;
+        let (kind, contents) = subpacket.split_first()?;
+        // The top bit only marks the subpacket as critical.
+        subpackets.push((kind & 0x7f, contents));
+        data = rest;
+    }
+    Some(subpackets)
+}
+
+fn packets(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, QuickgetError> {
+    let invalid = || QuickgetError::Parse("Invalid OpenPGP data.".into());
+    let mut packets = Vec::new();
+    while let Some((&header, rest)) = data.split_first() {
+        if header & 0x80 == 0 {
+            return Err(invalid());
+        }
+        let (tag, length, rest) = if header & 0x40 != 0 {
+            let (length, rest) = match rest {
+                [first @ 0..=191, rest @ ..] => (*first as usize, rest),
+                [first @ 192..=223, second, rest @ ..] => (((*first as usize - 192) << 8) + *second as usize + 192, rest),
+                [255, a, b, c, d, rest @ ..] => (u32::from_be_bytes([*a, *b, *c, *d]) as usize, rest),
+                // Partial lengths are only used for streamed data, which doesn't appear in keys or signatures.
+                _ => return Err(invalid()),
+            };
+            (header & 0x3f, length, rest)
+        } else {
+            let (length, rest) = match header & 0x03 {
+                0 => split(rest, 1).map(|(length, rest)| (length[0] as usize, rest)),
+                1 => split(rest, 2).map(|(length, rest)| (u16::from_be_bytes([length[0], length[1]]) as usize, rest)),
+                2 => split(rest, 4).map(|(length, rest)| (u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize, rest)),
+                _ => Some((rest.len(), rest)),
+            }.ok_or_else(invalid)?;
+            ((header >> 2) & 0x0f, length, rest)
+        };
+        let (body, rest) = split(rest, length).ok_or_else(invalid)?;
+        packets.push((tag, body));
+        data = rest;
+    }
+    Ok(packets)
+}
+
+fn mpi(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (bits, rest) = split(data, 2)?;
+    split(rest, (u16::from_be_bytes([bits[0], bits[1]]) as usize).div_ceil(8))
+}
+
+fn split(data: &[u8], at: usize) -> Option<(&[u8], &[u8])> {
+    (data.len() >= at).then(|| data.split_at(at))
+}
+
+// Decodes every armoured block in `data`. Anything that isn't armoured is assumed to be binary already.
+fn dearmor(data: &[u8]) -> Result<Vec<u8>, QuickgetError> {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return Ok(data.to_vec());
+    };
+    if !text.trim_start().starts_with("-----BEGIN PGP") {
+        return Ok(data.to_vec());
+    }
+
+    let mut decoded = Vec::new();
+    let mut lines = text.lines().map(str::trim);
+    while lines.by_ref().any(|line| line.starts_with("-----BEGIN PGP")) {
+        // Armour headers come before a blank line, although some encoders leave them out.
+        let block = lines.by_ref()
+            .take_while(|line| !line.starts_with("-----END PGP"))
+            .filter(|line| !line.contains(": "))
+            // The CRC24 checksum is redundant with the checks done on the packets themselves.
+            .filter(|line| !(line.starts_with('=') && line.len() == 5))
+            .collect::<String>();
+        decoded.extend(STANDARD.decode(block).map_err(|e| QuickgetError::Parse(format!("Invalid armour: {}", e)))?);
+    }
+    Ok(decoded)
+}
+
+// Text signatures are made over the text with CRLF line endings.
+fn canonical_text(text: &str) -> String {
+    text.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line)).join("\r\n")
+}
+
+// The fixtures are signed by throwaway keys, in the formats the distros publish: an armoured detached signature over
+// SHA256SUMS like Ubuntu's, a cleartext signed CHECKSUM file like Fedora's, made by a subkey, and a binary signature
+// over an image like KDE neon's.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CDIMAGE_KEY: &str = "F8C03E2AB36183B3594030D62DEBE393044E7A2A";
+    const RELEASE_KEY: &str = "2C93D244B6C335AFE648A7B95557A1BDD83AA950";
+
+    static CDIMAGE_KEYS: Keys = Keys {
+        name: "test-cdimage",
+        bundled: include_str!("../tests/fixtures/signature/cdimage.asc"),
+        fingerprints: &[CDIMAGE_KEY],
+        url: None,
+    };
+    static RELEASE_KEYS: Keys = Keys {
+        name: "test-release",
+        bundled: include_str!("../tests/fixtures/signature/release.asc"),
+        fingerprints: &[RELEASE_KEY],
+        url: None,
+    };
+    // The right keyring, but pinned to a different key.
+    static UNPINNED_KEYS: Keys = Keys {
+        name: "test-unpinned",
+        bundled: include_str!("../tests/fixtures/signature/cdimage.asc"),
+        fingerprints: &[RELEASE_KEY],
+        url: None,
+    };
+
+    const SUMS: &str = include_str!("../tests/fixtures/signature/SHA256SUMS");
+    const SUMS_SIGNATURE: &str = include_str!("../tests/fixtures/signature/SHA256SUMS.gpg");
+    const UNTRUSTED_SIGNATURE: &str = include_str!("../tests/fixtures/signature/SHA256SUMS-untrusted.gpg");
+    const CHECKSUM: &str = include_str!("../tests/fixtures/signature/Fedora-Workstation-40-1.14-x86_64-CHECKSUM");
+    const IMAGE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/signature/neon.iso");
+    const IMAGE_SIGNATURE: &[u8] = include_bytes!("../tests/fixtures/signature/neon.iso.sig");
+
+    // Each test records a different checksum, since the record of signed checksums is shared.
+    fn checksum(name: &str) -> String {
+        hex::encode(Sha256::digest(name))
+    }
+
+    #[test]
+    fn bundled_keys() {
+        let keys = load_keys(&RELEASE_KEYS).unwrap();
+        // The primary key, and its subkey, which is bound to it.
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|key| key.owner == RELEASE_KEY));
+        assert!(load_keys(&UNPINNED_KEYS).unwrap().is_empty());
+    }
+
+    // Each keyring must be a real export. An empty one would leave every checksum unsigned.
+    #[test]
+    #[ignore = "keys/ubuntu.asc, keys/kdeneon.asc and keys/fedora.asc are still empty"]
+    fn distro_keys() {
+        assert!(!load_keys(&UBUNTU_KEYS).unwrap().is_empty());
+        assert!(!load_keys(&KDE_NEON_KEYS).unwrap().is_empty());
+        for (release, _) in &FEDORA_KEYS {
+            assert!(!load_keys(fedora_release_keys(release).unwrap()).unwrap().is_empty(), "No keys for Fedora {}", release);
+        }
+    }
+
+    #[test]
+    fn detached_signature() {
+        let checksum = checksum("detached_signature");
+        check_checksum_file(&checksum, SUMS, SUMS_SIGNATURE, &CDIMAGE_KEYS, "SHA256SUMS").unwrap();
+        assert_eq!(signer(&checksum).as_deref(), Some(CDIMAGE_KEY));
+    }
+
+    #[test]
+    fn tampered_checksum_file() {
+        let checksum = checksum("tampered_checksum_file");
+        let tampered = SUMS.replacen("39d2", "39d3", 1);
+        let result = check_checksum_file(&checksum, &tampered, SUMS_SIGNATURE, &CDIMAGE_KEYS, "SHA256SUMS");
+        assert!(matches!(result, Err(QuickgetError::Verification(_))));
+        assert!(!has_signature(&checksum));
+    }
+
+    #[test]
+    fn untrusted_signature() {
+        let checksum = checksum("untrusted_signature");
+        check_checksum_file(&checksum, SUMS, UNTRUSTED_SIGNATURE, &CDIMAGE_KEYS, "SHA256SUMS").unwrap();
+        assert!(!has_signature(&checksum));
+    }
+
+    #[test]
+    fn unpinned_key() {
+        let checksum = checksum("unpinned_key");
+        check_checksum_file(&checksum, SUMS, SUMS_SIGNATURE, &UNPINNED_KEYS, "SHA256SUMS").unwrap();
+        assert!(!has_signature(&checksum));
+    }
+
+    #[test]
+    fn cleartext_signature() {
+        let (text, signer) = check_cleartext_file(CHECKSUM, &RELEASE_KEYS, "CHECKSUM").unwrap().unwrap();
+        assert_eq!(signer, RELEASE_KEY);
+        assert!(text.starts_with("# Fedora-Workstation-Live-x86_64-40-1.14.iso: 2295853056 bytes\nSHA256 ("));
+        assert!(!text.contains("PGP"));
+    }
+
+    #[test]
+    fn tampered_cleartext_file() {
+        let tampered = CHECKSUM.replacen("2295853056", "2295853057", 1);
+        let result = check_cleartext_file(&tampered, &RELEASE_KEYS, "CHECKSUM");
+        assert!(matches!(result, Err(QuickgetError::Verification(_))));
+        assert!(check_cleartext_file(CHECKSUM, &CDIMAGE_KEYS, "CHECKSUM").unwrap().is_none());
+    }
+
+    #[test]
+    fn image_signature() {
+        let checksum = checksum("image_signature");
+        defer_image_signature(&checksum, IMAGE_SIGNATURE.to_vec(), &CDIMAGE_KEYS);
+        assert!(has_signature(&checksum));
+        assert_eq!(check_image(IMAGE, &checksum).unwrap().as_deref(), Some(CDIMAGE_KEY));
+        assert_eq!(signer(&checksum).as_deref(), Some(CDIMAGE_KEY));
+    }
+
+    // Without any keys the signature can never be checked, so --require-signature refuses the image up front.
+    #[test]
+    fn image_signature_without_keys() {
+        let checksum = checksum("image_signature_without_keys");
+        defer_image_signature(&checksum, IMAGE_SIGNATURE.to_vec(), &UNPINNED_KEYS);
+        assert!(!has_signature(&checksum));
+    }
+
+    #[test]
+    fn tampered_image() {
+        let checksum = checksum("tampered_image");
+        let mut signature = IMAGE_SIGNATURE.to_vec();
+        // The last byte of the signature value.
+        *signature.last_mut().unwrap() ^= 0x01;
+        defer_image_signature(&checksum, signature, &CDIMAGE_KEYS);
+        assert!(matches!(check_image(IMAGE, &checksum), Err(QuickgetError::Verification(_))));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::utils::{cache_dir, Hash};
+use crate::error::QuickgetError;
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Images are kept in the cache directory, one directory per key. Files are keyed by their checksum where one is known,
+// and otherwise by the URLs they were downloaded from.
+fn store_dir() -> Option<PathBuf> {
+    cache_dir().map(|dir| dir.join("images"))
+}
+
+pub fn store_key(checksum: Option<&Hash>, urls: &[String]) -> String {
+    match checksum {
+        Some((_, digest)) => digest.to_lowercase(),
+        // Sorted so that choosing a different mirror doesn't change the key.
+        None => "url-".to_string() + &hex::encode(Sha256::digest(urls.iter().sorted().join("\n"))),
+    }
+}
+
+pub fn find_image(key: &str) -> Option<PathBuf> {
+    fs::read_dir(store_dir()?.join(key)).ok()?.flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.is_file())
+}
+
+// A VM may write to its image, and through a hard link or symlink that would change the stored copy too. Images keyed by
+// their checksum are hashed again before each use, so only those are shared that way.
+fn shareable(key: &str) -> bool {
+    !key.starts_with("url-")
+}
+
+pub fn remove_image(key: &str) -> io::Result<()> {
+    match store_dir() {
+        Some(dir) => fs::remove_dir_all(dir.join(key)),
+        None => Ok(()),
+    }
+}
+
+// Puts a stored image into a VM directory. Reflinks are preferred, since the VM gets an independent copy at no cost.
+// Hard links share the store's copy, and symlinks are the last resort when the store is on another filesystem.
+pub fn place_image(image: &Path, key: &str, destination: &str) -> io::Result<&'static str> {
+    match fs::symlink_metadata(destination) {
+        Ok(_) => fs::remove_file(destination)?,
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => (),
+    }
+    if reflink(image, Path::new(destination)).is_ok() {
+        return Ok("reflinked");
+    }
+    if !shareable(key) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "it has no checksum, and the filesystem can't reflink it"));
+    }
+    if fs::hard_link(image, destination).is_ok() {
+        return Ok("hard linked");
+    }
+    symlink(image, Path::new(destination))?;
+    Ok("symlinked")
+}
+
+// Adds a verified download to the store. Only reflinks and hard links are used, so storing an image never needs
+// extra space. Images on a different filesystem from the store are left out, as are images without a checksum which
+// can't be reflinked.
+pub fn add_image(path: &str, key: &str) {
+    let Some(dir) = store_dir().map(|dir| dir.join(key)) else {
+        return;
+    };
+    if find_image(key).is_some() {
+        return;
+    }
+    let Some(name) = Path::new(path).file_name() else {
+        return;
+    };
+    let destination = dir.join(name);
+    let stored = fs::create_dir_all(&dir).is_ok() && {
+        reflink(Path::new(path), &destination).is_ok() || (shareable(key) && fs::hard_link(path, &destination).is_ok())
+    };
+    if !stored {
+        fs::remove_dir(&dir).ok();
+        match shareable(key) {
+            true => eprintln!("Note: {} was not added to the image store, as it's on a different filesystem.", path),
+            false => eprintln!("Note: {} was not added to the image store, as it has no checksum and can't be reflinked.", path),
+        }
+    }
+}
+
+pub fn list_images() -> Result<(), QuickgetError> {
+    let images = stored_images()?;
+    if images.is_empty() {
+        println!("The image store is empty.");
+        return Ok(());
+    }
+    println!("Image,Size (MiB),VMs,Key");
+    for (key, path, metadata) in &images {
+        println!("{},{:.2},{},{}", path.file_name().unwrap_or_default().to_string_lossy(), metadata.len() as f64 / 1_048_576.0, vm_links(metadata), key);
+    }
+    Ok(())
+}
+
+// Removes images which aren't hard linked into any VM. VMs which symlink to an image will need to download it again.
+pub fn prune_images(all: bool) -> Result<(), QuickgetError> {
+    let mut freed = 0;
+    for (key, path, metadata) in stored_images()? {
+        if all || vm_links(&metadata) == 0 {
+            let dir = path.parent().expect("Stored images are inside a directory");
+            fs::remove_dir_all(dir).map_err(|e| QuickgetError::Io(format!("Unable to remove {}: {}", dir.display(), e)))?;
+            println!("Removed {} ({})", path.file_name().unwrap_or_default().to_string_lossy(), key);
+            freed += metadata.len();
+        }
+    }
+    println!("Freed {:.2} MiB.", freed as f64 / 1_048_576.0);
+    Ok(())
+}
+
+// The key, path and metadata of an image in the store.
+type StoredImage = (String, PathBuf, fs::Metadata);
+
+fn stored_images() -> Result<Vec<StoredImage>, QuickgetError> {
+    let dir = store_dir().ok_or(QuickgetError::Io("Unable to find the cache directory.".into()))?;
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Ok(vec![]);
+    };
+    Ok(entries.flatten()
+        .filter_map(|entry| {
+            let key = entry.file_name().to_string_lossy().into_owned();
+            let image = find_image(&key)?;
+            let metadata = fs::metadata(&image).ok()?;
+            Some((key, image, metadata))
+        })
+        .sorted_by_key(|(_, path, _)| path.file_name().map(|name| name.to_owned()))
+        .collect())
+}
+
+#[cfg(unix)]
+fn vm_links(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink().saturating_sub(1)
+}
+
+#[cfg(not(unix))]
+fn vm_links(_: &fs::Metadata) -> u64 {
+    0
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let source = fs::File::open(source)?;
+    let file = fs::File::create_new(destination)?;
+    // Safety: both file descriptors stay open for the duration of the call.
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FICLONE as _, source.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    drop(file);
+    fs::remove_file(destination).ok();
+    Err(e)
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    let source = std::ffi::CString::new(source.as_os_str().as_bytes())?;
+    let destination = std::ffi::CString::new(destination.as_os_str().as_bytes())?;
+    // Safety: both paths are valid C strings.
+    match unsafe { libc::clonefile(source.as_ptr(), destination.as_ptr(), 0) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn symlink(image: &Path, destination: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(image, destination)
+}
+
+#[cfg(not(unix))]
+fn symlink(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
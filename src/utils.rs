@@ -1,9 +1,20 @@
 use std::error::Error;
 use itertools::Itertools;
-use reqwest::header::HeaderMap;
-use std::sync::Mutex;
+use reqwest::{Certificate, NoProxy, Proxy, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, CACHE_CONTROL, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER};
+use rand::Rng;
+use std::time::{Duration, SystemTime};
+use crate::quickget::download_options;
+use crate::catalog::pinned_files;
+use crate::error::QuickgetError;
+use std::sync::{Mutex, OnceLock};
+use tokio::runtime::Runtime;
+use indicatif::MultiProgress;
 use rayon::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
 #[derive(Debug, Clone)]
 pub struct Distro {
@@ -15,13 +26,104 @@ pub struct Distro {
     pub checksum_function: Checksum,
     pub homepage: String,
     pub config: Config,
+    pub mirrors: Vec<(String, Vec<String>)>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Checksum {
     None,
-    Normal(fn(&str, &str, &str) -> Result<String, Box<dyn Error>>),
-    Manual(fn(&Vec<String>, &str, &str, &str) -> bool),
+    Normal(fn(&str, &str, &str) -> Result<Hash, Box<dyn Error>>),
+    Manual(fn(&Vec<String>, &str, &str, &str) -> Result<(), Box<dyn Error>>),
+}
+
+// Checksums are tagged with their algorithm where they're fetched, since a digest's length can't tell
+// e.g. SHA-256 from SHA3-256 or BLAKE3.
+pub type Hash = (Algorithm, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Algorithm {
+    #[serde(rename = "md5")]
+    Md5,
+    #[serde(rename = "sha1")]
+    Sha1,
+    #[serde(rename = "sha224")]
+    Sha224,
+    #[serde(rename = "sha256")]
+    Sha256,
+    #[serde(rename = "sha384")]
+    Sha384,
+    #[serde(rename = "sha512")]
+    Sha512,
+    #[serde(rename = "sha3-256")]
+    Sha3_256,
+    #[serde(rename = "sha3-384")]
+    Sha3_384,
+    #[serde(rename = "sha3-512")]
+    Sha3_512,
+    #[serde(rename = "blake2b")]
+    Blake2b,
+    #[serde(rename = "blake3")]
+    Blake3,
+}
+
+impl Algorithm {
+    // Accepts the spellings used by checksum files and metalinks, such as "SHA256", "sha-256", "SHA2-256" or "BLAKE2b-512".
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.replace(['-', '_'], "").to_lowercase().as_str() {
+            "md5" => Some(Self::Md5),
+            "sha1" => Some(Self::Sha1),
+            "sha224" | "sha2224" => Some(Self::Sha224),
+            "sha256" | "sha2256" => Some(Self::Sha256),
+            "sha384" | "sha2384" => Some(Self::Sha384),
+            "sha512" | "sha2512" => Some(Self::Sha512),
+            "sha3256" => Some(Self::Sha3_256),
+            "sha3384" => Some(Self::Sha3_384),
+            "sha3512" => Some(Self::Sha3_512),
+            "blake2b" | "blake2b512" => Some(Self::Blake2b),
+            "blake3" => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+    // Checksum files which don't name the algorithm on each line usually do in their own name, like "SHA256SUMS",
+    // "neon-user-current.sha256sum" or "b2sums.txt".
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let name = name.replace(['-', '_'], "").to_lowercase();
+        [
+            ("sha3512", Self::Sha3_512), ("sha3384", Self::Sha3_384), ("sha3256", Self::Sha3_256),
+            ("sha512", Self::Sha512), ("sha384", Self::Sha384), ("sha256", Self::Sha256), ("sha224", Self::Sha224),
+            ("sha1", Self::Sha1), ("md5", Self::Md5), ("blake2b", Self::Blake2b), ("b2sum", Self::Blake2b),
+            ("blake3", Self::Blake3), ("b3sum", Self::Blake3),
+        ].into_iter().find(|(tag, _)| name.contains(tag)).map(|(_, algorithm)| algorithm)
+    }
+    // Only for lockfiles written before checksums were tagged, which only ever held these.
+    pub fn guess(digest: &str) -> Option<Self> {
+        match digest.len() {
+            32 => Some(Self::Md5),
+            40 => Some(Self::Sha1),
+            64 => Some(Self::Sha256),
+            128 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::Md5 => "MD5",
+            Self::Sha1 => "SHA-1",
+            Self::Sha224 => "SHA-224",
+            Self::Sha256 => "SHA-256",
+            Self::Sha384 => "SHA-384",
+            Self::Sha512 => "SHA-512",
+            Self::Sha3_256 => "SHA3-256",
+            Self::Sha3_384 => "SHA3-384",
+            Self::Sha3_512 => "SHA3-512",
+            Self::Blake2b => "BLAKE2b",
+            Self::Blake3 => "BLAKE3",
+        };
+        write!(f, "{}", name)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +131,7 @@ pub enum URL {
     Format(String),
     Function(fn(&str, &str, &str) -> Result<Vec<String>, Box<dyn Error>>),
     PlusHeaders(fn(&str, &str, &str) -> Result<Vec<(String, HeaderMap)>, Box<dyn Error>>),
+    Metalink(fn(&str, &str, &str) -> Result<Vec<String>, Box<dyn Error>>),
 }
 
 #[derive(Debug, Clone)]
@@ -42,12 +145,19 @@ pub enum ReleaseEdition {
 #[derive(Debug, Clone)]
 pub enum Config {
     None,
-    Addition(fn(Vec<String>, &str, &str, &str) -> String),
+    Addition(fn(Vec<String>, &str, &str, &str) -> Result<String, Box<dyn Error>>),
     Overwrite(fn(Vec<String>, &str, &str, &str) -> Result<String, Box<dyn Error>>),
 }
 
 impl Distro {
-    pub fn get_url_iso(&self, release: &str, edition: &str, arch: &str) -> Vec<(String, HeaderMap, String)> {
+    pub fn get_url_iso(&self, release: &str, edition: &str, arch: &str) -> Result<Vec<(Vec<String>, HeaderMap, String)>, QuickgetError> {
+        self.try_get_url_iso(release, edition, arch).map_err(|e| QuickgetError::from(e).context("Unable to get URLs"))
+    }
+
+    pub fn try_get_url_iso(&self, release: &str, edition: &str, arch: &str) -> Result<Vec<(Vec<String>, HeaderMap, String)>, Box<dyn Error>> {
+        if let Some(files) = pinned_files(&self.name, arch, release, edition) {
+            return Ok(files.iter().map(|file| (file.urls.clone(), HeaderMap::new(), file.name.clone())).collect());
+        }
         let image_types = vec![".iso", ".img", ".dmg", ".chunklist", ".xz", ".raw", ".zip", ".tar", ".gz", ".msi"];
 
         let Distro { url, name, .. } = self;
@@ -59,80 +169,120 @@ impl Distro {
             }
         };
 
-        match url {
+        let urls: Vec<(Vec<String>, HeaderMap, String)> = match url {
             URL::Format(url_string) => {
                 let url_string = url_string.as_str().format(release, edition, arch);
                 let iso = iso_format(&url_string);
-                vec![(url_string, HeaderMap::new(), iso)]
+                vec![(vec![url_string], HeaderMap::new(), iso)]
             },
-            URL::Function(get_url) => {
-                match get_url(release, edition, arch) {
-                    Ok(urls) => urls.into_iter()
-                        .map(|url| {
-                            let iso = iso_format(&url);
-                            (url, HeaderMap::new(), iso)
-                        }).collect(),
-                    Err(e) => {
-                        eprintln!("Unable to get URLs: {}", e);
-                        std::process::exit(1);
-                    },
-                }
-            },
-            URL::PlusHeaders(get_info) => {
-                match get_info(release, edition, arch) {
-                    Ok(urls) => urls.into_iter()
-                        .map(|(url, header)| {
-                            let iso = iso_format(&url);
-                            (url, header, iso)
-                        }).collect(),
-                    Err(e) => {
-                        eprintln!("Unable to get URLs: {}", e);
-                        std::process::exit(1);
-                    },
-                }
+            URL::Function(get_url) => get_url(release, edition, arch)?.into_iter()
+                .map(|url| {
+                    let iso = iso_format(&url);
+                    (vec![url], HeaderMap::new(), iso)
+                }).collect(),
+            URL::PlusHeaders(get_info) => get_info(release, edition, arch)?.into_iter()
+                .map(|(url, header)| {
+                    let iso = iso_format(&url);
+                    (vec![url], header, iso)
+                }).collect(),
+            URL::Metalink(_) => self.metalink_files(release, edition, arch)?.into_iter()
+                .map(|file| (file.urls, HeaderMap::new(), file.name))
+                .collect(),
+        };
+
+        if download_options().mirror.is_some() && !urls.iter().flat_map(|(urls, ..)| urls).any(|url| self.mirrors.iter().any(|(upstream, _)| url.starts_with(upstream.as_str()))) {
+            eprintln!("WARNING: No mirrors are known for {}. Ignoring the preferred mirror.", self.pretty_name);
+        }
+        Ok(urls.into_iter().map(|(urls, headers, iso)| {
+            (urls.into_iter().flat_map(|url| self.candidate_urls(url)).unique().collect(), headers, iso)
+        }).collect())
+    }
+
+    fn metalink_files(&self, release: &str, edition: &str, arch: &str) -> Result<Vec<MetalinkFile>, Box<dyn Error>> {
+        match self.url {
+            URL::Metalink(get_metalinks) => get_metalinks(release, edition, arch)?.iter()
+                .map(|metalink| resolve_metalink(metalink))
+                .flatten_ok()
+                .collect(),
+            _ => Ok(vec![]),
+        }
+    }
+
+    // Lists the URLs a file can be downloaded from, in the order they should be tried:
+    // the user's preferred mirror, the upstream URL, and then any other known mirrors.
+    fn candidate_urls(&self, url: String) -> Vec<String> {
+        let Some((upstream, mirrors)) = self.mirrors.iter().find(|(upstream, _)| url.starts_with(upstream.as_str())) else {
+            return vec![url];
+        };
+        let path = &url[upstream.len()..];
+        let preferred = download_options().mirror.iter().map(|mirror| mirror.trim_end_matches('/').to_string() + "/" + path);
+        let mirrors = mirrors.iter().map(|mirror| mirror.to_string() + path);
+        preferred.chain(std::iter::once(url.clone())).chain(mirrors).dedup().collect()
+    }
+
+    // Returns the checksum of the file at `index` in the list returned by `get_url_iso`, if one is available.
+    // A checksum with a bad signature is an error, rather than just being unavailable.
+    pub fn get_checksum(&self, index: usize, release: &str, edition: &str, arch: &str) -> Result<Option<Hash>, QuickgetError> {
+        match self.try_get_checksum(index, release, edition, arch).map_err(QuickgetError::from) {
+            Err(e @ QuickgetError::Verification(_)) => Err(e),
+            Err(e) => {
+                eprintln!("\nUnable to get checksum: {}.\nQuickget will be unable to verify the integrity of the download\n.", e);
+                Ok(None)
             },
+            hash => hash,
         }
     }
-    pub fn has_checksum(&self, index: usize) -> bool {
+
+    pub fn try_get_checksum(&self, index: usize, release: &str, edition: &str, arch: &str) -> Result<Option<Hash>, Box<dyn Error>> {
+        if let Some(files) = pinned_files(&self.name, arch, release, edition) {
+            let Some((file, checksum)) = files.get(index).and_then(|file| Some(file).zip(file.checksum.as_ref())) else {
+                return Ok(None);
+            };
+            let algorithm = file.algorithm.or_else(|| Algorithm::guess(checksum))
+                .ok_or_else(|| format!("The lockfile doesn't say which algorithm the checksum of {} uses.", file.name))?;
+            return Ok(Some((algorithm, checksum.clone())));
+        }
+        // Metalinks carry their own hashes, so there's no need for a separate lookup.
+        if let URL::Metalink(_) = self.url {
+            return Ok(self.metalink_files(release, edition, arch)?.into_iter().nth(index).and_then(|file| file.hash));
+        }
         match self.checksum_function {
-            Checksum::Normal(_) if index == 0 => true,
-            _ => false,
+            Checksum::Normal(get_hash) if index == 0 => Ok(Some(get_hash(release, edition, arch)?)),
+            _ => Ok(None),
         }
     }
 
-    pub fn get_checksum(&self, release: &str, edition: &str, arch: &str) -> Option<String> {
-        match self.checksum_function {
-            Checksum::Normal(get_hash) => match get_hash(release, edition, arch) {
-                Ok(hash) => Some(hash),
-                Err(e) => {
-                    eprintln!("\nUnable to get checksum: {}.\nQuickget will be unable to verify the integrity of the download\n.", e);
-                        None
-                },
+    // Flattens the different ways of listing releases into release/edition pairs.
+    pub fn releases(&self) -> Result<Vec<(String, Vec<String>)>, Box<dyn Error>> {
+        Ok(match &self.release_edition {
+            ReleaseEdition::Basic(releases, editions) => releases.iter().map(|release| (release.clone(), editions.clone())).collect(),
+            ReleaseEdition::Unique(releases) => releases.clone(),
+            ReleaseEdition::OnlineBasic(get_releases) => {
+                let (releases, editions) = get_releases(&self.arch)?;
+                releases.into_iter().map(|release| (release, editions.clone())).collect()
             },
-            _ => None,
-        }
+            ReleaseEdition::OnlineUnique(get_info) => get_info(&self.arch)?,
+        })
     }
 
-    pub fn verify_after(&self, paths: &Vec<String>, release: &str, edition: &str, arch: &str) -> Option<bool> {
+    pub fn verify_after(&self, paths: &Vec<String>, release: &str, edition: &str, arch: &str) -> Option<Result<(), QuickgetError>> {
         match self.checksum_function {
-            Checksum::Manual(verify) => Some(verify(paths, release, edition, arch)),
+            Checksum::Manual(verify) => Some(verify(paths, release, edition, arch).map_err(QuickgetError::from)),
             _ => None,
         }
     }
 }
 
 pub trait Validation {
-    fn validate_parameters(&self, os: &str, release: &str, edition: &str, arch: &str) -> &Distro;
+    fn validate_parameters(&self, os: &str, release: &str, edition: &str, arch: &str) -> Result<&Distro, QuickgetError>;
     fn list_oses(&self) -> String;
     fn list_releases(&self, releases: Vec<(String, Vec<String>)>) -> String;
 }
 
 impl Validation for Vec<Distro> {
-    fn validate_parameters(&self, os: &str, release: &str, edition: &str, arch: &str) -> &Distro {
+    fn validate_parameters(&self, os: &str, release: &str, edition: &str, arch: &str) -> Result<&Distro, QuickgetError> {
         if os.len() == 0 {
-            eprintln!("ERROR! You must specify an operating system.");
-            println!(" - Operating systems: {}", self.list_oses());
-            std::process::exit(1);
+            return Err(QuickgetError::unsupported("You must specify an operating system.", format!(" - Operating systems: {}", self.list_oses())));
         }
 
         let distros: Vec<&Distro> = match self.iter().any(|distro| distro.name == os && distro.arch == arch) {
@@ -140,85 +290,90 @@ impl Validation for Vec<Distro> {
                 false => self.iter().filter(|distro| distro.name == os).collect(),
         };
         if distros.len() == 0 {
-            eprintln!("ERROR! {} is not a supported OS.", os);
-            println!(" - Operating systems: {}", self.list_oses());
-            std::process::exit(1);
+            return Err(QuickgetError::unsupported(format!("{} is not a supported OS.", os), format!(" - Operating systems: {}", self.list_oses())));
         }
         if arch != std::env::consts::ARCH && !distros.iter().any(|distro| distro.arch == arch) {
-            eprintln!("Architecture {} not available for {}. Please use one of the available architectures, or don't specify an architecture to automatically select one.", arch, distros[0].pretty_name);
-            println!(" - Architectures: {}", distros.iter().map(|distro| &*distro.arch).dedup().collect::<Vec<_>>().join(" "));
-            std::process::exit(1);
+            return Err(QuickgetError::unsupported(
+                format!("Architecture {} not available for {}. Please use one of the available architectures, or don't specify an architecture to automatically select one.", arch, distros[0].pretty_name),
+                format!(" - Architectures: {}", distros.iter().map(|distro| &*distro.arch).dedup().collect::<Vec<_>>().join(" ")),
+            ));
         }
 
         let pretty_name = distros[0].pretty_name.clone();
 
         let mut data: Vec<(String, Vec<String>)> = Vec::new();
+        let mut failed = 0;
+        let mut last_error = None;
+        let total = distros.len();
 
         for distro in distros {
             match &distro.release_edition {
                 ReleaseEdition::Basic(releases, editions) => {
                     if releases.contains(&release.to_string()) && { editions.len() == 0 || editions.contains(&edition.to_string()) } {
-                        return distro;
+                        return Ok(distro);
                     }
                     data.append(&mut releases.iter().map(|release| (release.to_string(), editions.clone())).collect());
                 },
                 ReleaseEdition::Unique(releases) => {
                     if releases.iter().any(|(rel, editions)| rel == release && { editions.len() == 0 || editions.contains(&edition.to_string()) }) {
-                        return distro;
+                        return Ok(distro);
                     }
                     data.append(&mut releases.clone());
                 },
                 ReleaseEdition::OnlineBasic(get_releases) => match get_releases(&distro.arch) {
                     Ok((releases, editions)) => {
                         if releases.contains(&release.to_string()) && { editions.len() == 0 || editions.contains(&edition.to_string()) } {
-                            return distro;
+                            return Ok(distro);
                         }
                         data.append(&mut releases.into_iter().map(|release| (release, editions.clone())).collect());
                     },
                     Err(e) => {
-                        eprintln!("Unable to get releases for {}: {}", distro.name, e);
-                        std::process::exit(1);
+                        eprintln!("Unable to get releases for {} ({}): {}", distro.name, distro.arch, e);
+                        failed += 1;
+                        last_error = Some(e);
                     },
                 },
                 ReleaseEdition::OnlineUnique(get_info) => match get_info(&distro.arch) {
                     Ok(mut releases) => {
                         if releases.iter().any(|(rel, editions)| rel == release && { editions.len() == 0 || editions.contains(&edition.to_string()) }) {
-                            return distro;
+                            return Ok(distro);
                         }
                         data.append(&mut releases);
                     },
                     Err(e) => {
-                        eprintln!("Unable to get releases for {}: {}", distro.name, e);
-                        std::process::exit(1);
+                        eprintln!("Unable to get releases for {} ({}): {}", distro.name, distro.arch, e);
+                        failed += 1;
+                        last_error = Some(e);
                     },
                 },
             }
         }
 
+        if let Some(e) = last_error.filter(|_| failed == total) {
+            return Err(QuickgetError::from(e).context(&format!("No releases of {} could be found", pretty_name)));
+        }
+        if failed > 0 {
+            eprintln!("WARNING: Some releases could not be fetched, so the list below may be incomplete.");
+        }
+
         if release.len() == 0 {
-            eprintln!("ERROR! You must specify a release.");
-            println!("{}", self.list_releases(data));
-            std::process::exit(1);
+            return Err(QuickgetError::unsupported("You must specify a release.", self.list_releases(data)));
         }
 
         for (rel, editions) in &data {
             if rel == release {
                 if !editions.contains(&edition.to_string()) {
-                    if edition.is_empty() {
-                        eprintln!("ERROR! You must specify an edition.");
-                    } else {
-                        eprintln!("ERROR! {} is not a supported {} {} edition", edition, pretty_name, release);
-                    }
-                    println!(" - Editions: {}", editions.join(" "));
-                    std::process::exit(1);
+                    let message = match edition.is_empty() {
+                        true => "You must specify an edition.".to_string(),
+                        false => format!("{} is not a supported {} {} edition", edition, pretty_name, release),
+                    };
+                    return Err(QuickgetError::unsupported(message, format!(" - Editions: {}", editions.join(" "))));
                 } else {
                     panic!("ERROR! Somehow an OS was not returned despite being found in the list. This should never happen.");
                 }
             }
         }
-        eprintln!("ERROR! {} is not a supported {} release.", release, pretty_name);
-        println!("{}", self.list_releases(data));
-        std::process::exit(1);
+        Err(QuickgetError::unsupported(format!("{} is not a supported {} release.", release, pretty_name), self.list_releases(data)))
     }
 
 
@@ -260,77 +415,43 @@ struct DistroList<'a> {
 }
 
 pub trait List {
-    fn list(&self, is_json: bool);
+    fn list(&self, is_json: bool) -> Result<(), QuickgetError>;
 }
 
 impl List for Vec<Distro> {
-    fn list(&self, is_json: bool) {
-        let data = self.par_iter().map(|distro| {
+    // A distro whose releases can't be fetched is reported on stderr and left out, rather than breaking the whole list.
+    fn list(&self, is_json: bool) -> Result<(), QuickgetError> {
+        let results = self.par_iter().map(|distro| {
             let png = "https://quickemu-project.github.io/quickemu-icons/png/{OS}/{OS}-quickemu-white-pinkbg.png".replace("{OS}", &distro.name);
             let svg = "https://quickemu-project.github.io/quickemu-icons/svg/{OS}/{OS}-quickemu-white-pinkbg.svg".replace("{OS}", &distro.name);
-            match &distro.release_edition {
-                ReleaseEdition::Basic(releases, editions) => releases.iter().map(|release| {
-                    if editions.is_empty() {
-                        vec![DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: "".into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }]
-                    } else {
-                        editions.iter().map(|edition| {
-                            DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: edition.into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }
-                        }).collect::<Vec<DistroList>>()
-                    }
-                }).flatten().collect::<Vec<DistroList>>(),
-                        
-                ReleaseEdition::Unique(releases) => releases.iter().map(|(release, editions)| {
-                    if editions.is_empty() {
-                        vec![DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: "".into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }]
-                    } else {
-                        editions.iter().map(|edition| {
-                            DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: edition.into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }
-                        }).collect::<Vec<DistroList>>()
-                    }
-                }).flatten().collect::<Vec<DistroList>>(),
-                ReleaseEdition::OnlineBasic(get_releases) => match get_releases(&distro.arch) {
-                    Ok((releases, editions)) => releases.iter().map(|release| {
-                        if editions.is_empty() {
-                            vec![DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: "".into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }]
-                        } else {
-                            editions.iter().map(|edition| {
-                                DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: edition.into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }
-                            }).collect::<Vec<DistroList>>()
-                        }
-                    }).flatten().collect::<Vec<DistroList>>(),
-                    Err(e) => {
-                        eprintln!("Unable to get releases for {}: {}", distro.name, e);
-                        std::process::exit(1);
-                    },
-                },
-                ReleaseEdition::OnlineUnique(get_info) => match get_info(&distro.arch) {
-                    Ok(releases) => releases.iter().map(|(release, editions)| {
-                        if editions.is_empty() {
-                            vec![DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: "".into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }]
-                        } else {
-                            editions.iter().map(|edition| {
-                                DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.into(), option: edition.into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }
-                            }).collect::<Vec<DistroList>>()
-                        }
-                    }).flatten().collect::<Vec<DistroList>>(),
-                    Err(e) => {
-                        eprintln!("Unable to get releases for {}: {}", distro.name, e);
-                        std::process::exit(1);
-                    },
-                },
-            }
-        }).flatten().collect::<Vec<DistroList>>();
+            let releases = distro.releases().map_err(|e| format!("Unable to get releases for {} ({}): {}", distro.name, distro.arch, e))?;
+            Ok(releases.into_iter().map(|(release, editions)| {
+                if editions.is_empty() {
+                    vec![DistroList { display_name: &distro.pretty_name, os: &distro.name, release, option: "".into(), arch: &distro.arch, png: png.clone(), svg: svg.clone() }]
+                } else {
+                    editions.into_iter().map(|edition| {
+                        DistroList { display_name: &distro.pretty_name, os: &distro.name, release: release.clone(), option: edition, arch: &distro.arch, png: png.clone(), svg: svg.clone() }
+                    }).collect::<Vec<DistroList>>()
+                }
+            }).flatten().collect::<Vec<DistroList>>())
+        }).collect::<Vec<Result<Vec<DistroList>, String>>>();
+
+        let (data, errors): (Vec<_>, Vec<_>) = results.into_iter().partition_result();
+        let data = data.into_iter().flatten().collect::<Vec<DistroList>>();
 
         if is_json {
-            println!("{}", serde_json::to_string_pretty(&data).unwrap());
+            println!("{}", serde_json::to_string_pretty(&data)?);
         } else {
             println!("Display Name,OS,Release,Option,Arch,PNG,SVG\n{}", data.iter().map(|distro| {
                 format!("{},{},{},{},{},{},{}", distro.display_name, distro.os, distro.release, distro.option, distro.arch, distro.png, distro.svg)
             }).collect::<Vec<String>>().join("\n"));
         }
 
-
-        std::process::exit(0);
+        if !errors.is_empty() {
+            errors.iter().for_each(|error| eprintln!("{}", error));
+            return Err(QuickgetError::Partial(format!("{} of {} distros could not be listed.", errors.len(), self.len())));
+        }
+        Ok(())
     }
 }
 
@@ -346,19 +467,475 @@ impl FormatUrl for &str {
 
 static CACHE_PAGES: Mutex<Vec<(String, String)>> = Mutex::new(vec![]);
 
+// Pages are also kept on disk between runs. Each entry records how long it can be used without asking the server
+// again, and the validators needed to cheaply check whether it has changed once that time is up.
+#[derive(Serialize, Deserialize)]
+struct CachedPage {
+    url: String,
+    fetched: u64,
+    ttl: u64,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+// Used when the server doesn't say how long a page may be cached for.
+const DEFAULT_PAGE_TTL: u64 = 21_600;
+
+impl CachedPage {
+    fn is_fresh(&self) -> bool {
+        unix_time().saturating_sub(self.fetched) < self.ttl
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+pub fn cache_dir() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("quickget"))
+}
+
+fn page_cache_path(url: &str) -> Option<PathBuf> {
+    Some(cache_dir()?.join("pages").join(hex::encode(Sha256::digest(url)) + ".json"))
+}
+
+fn read_cached_page(url: &str) -> Option<CachedPage> {
+    let page: CachedPage = serde_json::from_slice(&std::fs::read(page_cache_path(url)?).ok()?).ok()?;
+    (page.url == url).then_some(page)
+}
+
+// The cache is only an optimisation, so failing to write to it isn't an error.
+fn write_cached_page(page: &CachedPage) {
+    let Some(path) = page_cache_path(&page.url) else {
+        return;
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).ok();
+    }
+    if let Ok(json) = serde_json::to_vec(page) {
+        std::fs::write(path, json).ok();
+    }
+}
+
+pub fn clear_cache() -> Result<(), QuickgetError> {
+    let Some(dir) = cache_dir() else {
+        return Err(QuickgetError::Io("Unable to find the cache directory.".into()));
+    };
+    match std::fs::remove_dir_all(dir.join("pages")) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(QuickgetError::Io(format!("Unable to clear {}: {}", dir.display(), e))),
+        _ => Ok(()),
+    }
+}
+
+// In offline mode, the cached pages are the catalog snapshot. Describes how many there are and how old they are.
+pub fn describe_snapshot() -> Result<String, QuickgetError> {
+    let dir = cache_dir().ok_or(QuickgetError::Io("Unable to find the cache directory.".into()))?.join("pages");
+    let fetched = std::fs::read_dir(&dir).into_iter().flatten().flatten()
+        .filter_map(|entry| serde_json::from_slice::<CachedPage>(&std::fs::read(entry.path()).ok()?).ok())
+        .map(|page| page.fetched)
+        .collect::<Vec<u64>>();
+    let (Some(oldest), Some(newest)) = (fetched.iter().min(), fetched.iter().max()) else {
+        return Err(QuickgetError::Network("No catalog snapshot is available. Run quickget while online to save one.".into()));
+    };
+    let now = unix_time();
+    Ok(format!("using a snapshot of {} pages, fetched between {} and {} ago", fetched.len(), format_age(now.saturating_sub(*newest)), format_age(now.saturating_sub(*oldest))))
+}
+
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=119 => format!("{} seconds", seconds),
+        120..=7199 => format!("{} minutes", seconds / 60),
+        7200..=172_799 => format!("{} hours", seconds / 3600),
+        _ => format!("{} days", seconds / 86400),
+    }
+}
+
+// Returns how long a response may be cached for, or None if it mustn't be stored at all.
+fn cache_ttl(headers: &HeaderMap) -> Option<u64> {
+    let Some(cache_control) = headers.get(CACHE_CONTROL).and_then(|value| value.to_str().ok()) else {
+        return Some(DEFAULT_PAGE_TTL);
+    };
+    let directives = cache_control.split(',').map(|directive| directive.trim().to_lowercase()).collect::<Vec<_>>();
+    if directives.iter().any(|directive| directive == "no-store") {
+        return None;
+    }
+    if directives.iter().any(|directive| directive == "no-cache") {
+        return Some(0);
+    }
+    Some(directives.iter().find_map(|directive| directive.strip_prefix("max-age=")?.parse().ok()).unwrap_or(DEFAULT_PAGE_TTL))
+}
+
+pub fn not_in_snapshot(url: &str) -> QuickgetError {
+    QuickgetError::Network(format!("{} isn't in the offline snapshot. Run quickget without --offline to fetch it", url))
+}
+
+// The in-memory cache is only locked to look pages up and to add them, so that a slow or retrying server doesn't hold up
+// fetches running on other threads.
 pub fn collect_page(url: String) -> Result<String, Box<dyn Error>> {
-    let mut cache = CACHE_PAGES.lock().unwrap();
-    match cache.iter().find(|(website_url, _)| website_url.to_string() == url) {
-        Some((_, contents)) => Ok(contents.to_string()),
-        None => {
-            let request = reqwest::blocking::get(&url)?;
+    if let Some((_, contents)) = CACHE_PAGES.lock().unwrap().iter().find(|(website_url, _)| *website_url == url) {
+        return Ok(contents.to_string());
+    }
+
+    if download_options().offline {
+        return match read_cached_page(&url) {
+            Some(page) => Ok(remember_page(url, page.body)),
+            None => Err(not_in_snapshot(&url).into()),
+        };
+    }
+
+    let cached = match download_options().refresh {
+        true => None,
+        false => read_cached_page(&url),
+    };
+    if let Some(page) = cached.as_ref().filter(|page| page.is_fresh()) {
+        return Ok(remember_page(url, page.body.clone()));
+    }
+
+    let send = || {
+        let mut request = context().blocking_client.get(&url);
+        if let Some(page) = &cached {
+            if let Some(etag) = &page.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &page.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        request.send()
+    };
+
+    let retries = download_options().retries;
+    let mut failures = 0;
+    let request = loop {
+        let (error, delay) = match send() {
+            Ok(request) if request.status().is_server_error() || request.status() == StatusCode::TOO_MANY_REQUESTS => {
+                (format!("Server responded with {}", request.status()), retry_after(request.headers()))
+            },
+            Ok(request) => break request,
+            Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => (e.to_string(), None),
+            Err(e) => return Err(e.into()),
+        };
+        if failures >= retries {
+            return Err(QuickgetError::Network(format!("{} while fetching {}", error, url)).into());
+        }
+        let delay = delay.unwrap_or_else(|| backoff(failures));
+        failures += 1;
+        eprintln!("{} while fetching {}. Retrying in {:.1}s ({}/{}).", error, url, delay.as_secs_f64(), failures, retries);
+        std::thread::sleep(delay);
+    };
+
+    let ttl = cache_ttl(request.headers());
+    let header = |name| request.headers().get(name).and_then(|value: &HeaderValue| value.to_str().ok()).map(String::from);
+    let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+
+    let body = match cached {
+        Some(mut page) if request.status() == StatusCode::NOT_MODIFIED => {
+            page.fetched = unix_time();
+            page.ttl = ttl.unwrap_or(0);
+            write_cached_page(&page);
+            page.body
+        },
+        _ => {
             if request.content_length().unwrap_or(u64::MAX) > 10_485_760 {
                 return Err("The 'collect_page' function is not intended to collect large files (>10MiB).".into());
             }
+            let success = request.status().is_success();
             let body = request.text()?;
+            if let Some(ttl) = ttl.filter(|_| success) {
+                write_cached_page(&CachedPage { url: url.clone(), fetched: unix_time(), ttl, etag, last_modified, body: body.clone() });
+            }
+            body
+        },
+    };
+    Ok(remember_page(url, body))
+}
+
+// Another thread may have fetched the same page in the meantime, in which case its copy is kept.
+fn remember_page(url: String, body: String) -> String {
+    let mut cache = CACHE_PAGES.lock().unwrap();
+    match cache.iter().find(|(website_url, _)| *website_url == url) {
+        Some((_, contents)) => contents.clone(),
+        None => {
             cache.push((url, body.clone()));
-            Ok(body)
+            body
+        },
+    }
+}
+
+static NETWORK_SETTINGS: OnceLock<(Option<Proxy>, Vec<Certificate>)> = OnceLock::new();
+
+// Reads the proxy and CA bundles given on the command line. Must be called after the download options are set,
+// so that invalid settings are reported up front rather than by whichever request happens to come first.
+pub fn configure_network() -> Result<(), QuickgetError> {
+    let options = download_options();
+    let proxy = match &options.proxy {
+        Some(url) => {
+            let no_proxy = match &options.no_proxy {
+                Some(hosts) => NoProxy::from_string(hosts),
+                None => NoProxy::from_env(),
+            };
+            Some(Proxy::all(url).map_err(|e| QuickgetError::Network(format!("Invalid proxy {}: {}", url, e)))?.no_proxy(no_proxy))
+        },
+        None => None,
+    };
+    let certificates = options.ca_bundles.iter().map(|path| {
+        let bundle = std::fs::read(path).map_err(|e| QuickgetError::Io(format!("Unable to read CA bundle {}: {}", path, e)))?;
+        Certificate::from_pem_bundle(&bundle).map_err(|e| QuickgetError::Parse(format!("Invalid CA bundle {}: {}", path, e)))
+    }).flatten_ok().collect::<Result<Vec<Certificate>, QuickgetError>>()?;
+
+    NETWORK_SETTINGS.set((proxy, certificates)).expect("The network has already been configured");
+    Ok(())
+}
+
+// Without an explicit proxy, reqwest uses HTTP_PROXY, HTTPS_PROXY and ALL_PROXY (which may be a socks5:// URL),
+// skipping hosts listed in NO_PROXY.
+fn network_settings() -> &'static (Option<Proxy>, Vec<Certificate>) {
+    NETWORK_SETTINGS.get_or_init(|| (None, vec![]))
+}
+
+// Everything that talks to the network shares one runtime and one pair of clients, so that connections
+// (and HTTP/2 sessions) are pooled across requests rather than set up again for each file or page.
+// Progress bars are drawn together, so that concurrent downloads don't overwrite each other's output.
+pub struct Context {
+    pub runtime: Runtime,
+    pub client: reqwest::Client,
+    pub blocking_client: reqwest::blocking::Client,
+    pub progress: MultiProgress,
+}
+
+static CONTEXT: OnceLock<Context> = OnceLock::new();
+
+// The clients honour the network settings, so this mustn't be called before `configure_network`.
+pub fn context() -> &'static Context {
+    CONTEXT.get_or_init(|| {
+        let (proxy, certificates) = network_settings();
+        let mut builder = reqwest::Client::builder();
+        let mut blocking_builder = reqwest::blocking::Client::builder();
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(proxy.clone());
+            blocking_builder = blocking_builder.proxy(proxy.clone());
+        }
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate.clone());
+            blocking_builder = blocking_builder.add_root_certificate(certificate.clone());
+        }
+        Context {
+            runtime: Runtime::new().expect("Unable to start the async runtime"),
+            client: builder.build().expect("Unable to initialise the HTTP client"),
+            blocking_client: blocking_builder.build().expect("Unable to initialise the HTTP client"),
+            progress: MultiProgress::new(),
         }
+    })
+}
+
+// Exponential backoff starting at one second and capped at a minute. The jitter keeps
+// parallel downloads from all retrying at the same moment.
+pub fn backoff(failures: u32) -> Duration {
+    let delay = Duration::from_secs(1 << failures.min(6)).min(Duration::from_secs(60));
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+// Retry-After may either be a number of seconds or an HTTP date.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => httpdate::parse_http_date(value).ok()?.duration_since(SystemTime::now()).ok(),
+    }
+}
+
+pub struct MetalinkFile {
+    pub name: String,
+    pub hash: Option<Hash>,
+    pub urls: Vec<String>,
+}
+
+const METALINK_NAMESPACE: &str = "urn:ietf:params:xml:ns:metalink";
+
+// Parses a Metalink 4 (RFC 5854) document into the files it describes. Mirrors are ordered by
+// priority, and the strongest hash that quickget knows how to check is kept for each file.
+pub fn resolve_metalink(url: &str) -> Result<Vec<MetalinkFile>, Box<dyn Error>> {
+    let body = collect_page(url.to_string())?;
+    let document = roxmltree::Document::parse(&body).map_err(|e| format!("Unable to parse metalink {}: {}", url, e))?;
+    let root = document.root_element();
+    if !root.has_tag_name((METALINK_NAMESPACE, "metalink")) {
+        return Err(format!("{} is not a Metalink 4 document.", url).into());
+    }
+
+    let files = root.children().filter(|node| node.has_tag_name((METALINK_NAMESPACE, "file"))).map(|file| {
+        let name = file.attribute("name").ok_or(format!("A file in metalink {} has no name.", url))?;
+        // File names may contain directories, which shouldn't end up in the VM directory.
+        let name = name.rsplit('/').next().unwrap_or(name).to_string();
+
+        let elements = |tag: &'static str| file.children().filter(move |node| node.has_tag_name((METALINK_NAMESPACE, tag)));
+        let hash = [Algorithm::Sha512, Algorithm::Sha384, Algorithm::Sha256, Algorithm::Sha224, Algorithm::Sha1, Algorithm::Md5].into_iter().find_map(|algorithm| {
+            elements("hash")
+                .find(|hash| hash.attribute("type").and_then(Algorithm::from_name) == Some(algorithm))
+                .and_then(|hash| hash.text())
+                .map(|hash| (algorithm, hash.trim().to_lowercase()))
+        });
+        let urls = elements("url")
+            .filter_map(|mirror| {
+                let priority = mirror.attribute("priority").and_then(|priority| priority.parse::<u32>().ok()).unwrap_or(u32::MAX);
+                mirror.text().map(|text| (priority, text.trim().to_string()))
+            })
+            .filter(|(_, url)| url.starts_with("https://") || url.starts_with("http://"))
+            .sorted_by_key(|(priority, _)| *priority)
+            .map(|(_, url)| url)
+            .collect::<Vec<String>>();
+        if urls.is_empty() {
+            return Err(format!("Metalink {} lists no HTTP mirrors for {}.", url, name).into());
+        }
+        Ok(MetalinkFile { name, hash, urls })
+    }).collect::<Result<Vec<MetalinkFile>, Box<dyn Error>>>()?;
+
+    if files.is_empty() {
+        return Err(format!("Metalink {} doesn't describe any files.", url).into());
     }
+    Ok(files)
 }
 
+// Parses a checksum file into a map from file names to their checksums. These formats are understood:
+//   GNU coreutils     "<hash>  <file>" or "<hash> *<file>", with the algorithm given by `algorithm`
+//   BSD and OpenSSL   "SHA256 (<file>) = <hash>", as used by Fedora's CHECKSUM files
+//   Single hash       "<hash>", as in most ".sha256" files, which is stored under an empty name
+// Clearsigned files are read without checking their signature, which is up to `signature::check_cleartext_file`.
+pub fn parse_checksums(contents: &str, algorithm: Option<Algorithm>) -> BTreeMap<String, Hash> {
+    let mut lines = contents.lines().map(str::trim_end).peekable();
+    if lines.peek() == Some(&"-----BEGIN PGP SIGNED MESSAGE-----") {
+        // Armor headers run until the first blank line.
+        lines.by_ref().take_while(|line| !line.is_empty()).for_each(drop);
+    }
+
+    lines.take_while(|line| *line != "-----BEGIN PGP SIGNATURE-----")
+        .map(|line| line.strip_prefix("- ").unwrap_or(line).trim_start())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| parse_bsd_checksum(line).or_else(|| parse_gnu_checksum(line, algorithm?)))
+        .map(|(file, hash)| (file.strip_prefix("./").map(str::to_string).unwrap_or(file), hash))
+        .collect()
+}
+
+fn is_hex(digest: &str) -> bool {
+    !digest.is_empty() && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn parse_bsd_checksum(line: &str) -> Option<(String, Hash)> {
+    let (tag, rest) = line.split_once('(')?;
+    let (file, digest) = rest.rsplit_once(')')?;
+    let digest = digest.trim_start().strip_prefix('=')?.trim();
+    let algorithm = Algorithm::from_name(tag.trim())?;
+    is_hex(digest).then(|| (file.to_string(), (algorithm, digest.to_lowercase())))
+}
+
+fn parse_gnu_checksum(line: &str, algorithm: Algorithm) -> Option<(String, Hash)> {
+    // Coreutils escapes file names containing backslashes or newlines, and marks those lines with a backslash.
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (digest, file) = line.split_once(' ').unwrap_or((line, ""));
+    let file = file.strip_prefix(['*', ' ']).unwrap_or(file);
+    let file = match escaped {
+        true => file.replace("\\\\", "\u{0}").replace("\\n", "\n").replace('\u{0}', "\\"),
+        false => file.to_string(),
+    };
+    is_hex(digest).then(|| (file, (algorithm, digest.to_lowercase())))
+}
+
+// Looks up the first file accepted by `file` in a checksum file fetched from `url`, and returns its name and checksum.
+// Only the last part of the URL is used for the algorithm, since mirrors' paths may name others. A single-hash file
+// applies to whichever file it was published next to, so it's returned with an empty name.
+pub fn checksum_from(url: &str, contents: &str, file: impl Fn(&str) -> bool) -> Result<(String, Hash), Box<dyn Error>> {
+    let name = url.rsplit('/').next().unwrap_or(url);
+    let checksums = parse_checksums(contents, Algorithm::from_file_name(name));
+    match checksums.get("").filter(|_| checksums.len() == 1) {
+        Some(hash) => Ok((String::new(), hash.clone())),
+        None => checksums.into_iter().find(|(listed, _)| file(listed)).ok_or(format!("No matching file is listed in {}.", name).into()),
+    }
+}
+
+pub fn fetch_checksum(url: &str, file: impl Fn(&str) -> bool) -> Result<(String, Hash), Box<dyn Error>> {
+    checksum_from(url, &collect_page(url.to_string())?, file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUNTU_SUMS: &str = "\
+ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb *ubuntu-24.04-desktop-amd64.iso
+3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d *ubuntu-24.04-live-server-amd64.iso
+";
+    const FEDORA_CHECKSUM: &str = "\
+-----BEGIN PGP SIGNED MESSAGE-----
+Hash: SHA256
+
+# Fedora-Workstation-Live-x86_64-40-1.14.iso: 2295853056 bytes
+SHA256 (Fedora-Workstation-Live-x86_64-40-1.14.iso) = 2E7D2C03A9507AE265ECF5B5356885A53393A2029D241394997265A1A25AEFC6
+- SHA256 (Fedora-Workstation-Live-osb-40-1.14.x86_64.iso) = 18ac3e7343f016890c510e93f935261169d9e3f565436429830faf0934f4f8e4
+-----BEGIN PGP SIGNATURE-----
+
+iQIzBAEBCAAdFiEEFd+Zrvhhfnhj7mRLCidwfqFbecwFAmYhTkgACgkQCidwfqFb
+=Wk3n
+-----END PGP SIGNATURE-----
+";
+    const KDE_NEON_SUMS: &str = "3f79bb7b435b05321651daefd374cdc681dc06faa65e374e38337b88ca046dea  neon-user-20240613-0744.iso\n";
+    const FREEBSD_CHECKSUM: &str = "\
+SHA512 (FreeBSD-14.1-RELEASE-amd64-disc1.iso) = a4abd4448c49562d828115d13a1fccea927f52b4d5459297f8b43e42da89238bc13626e43dcb38ddb082488927ec904fb42057443983e88585179d50551afe62
+";
+
+    #[test]
+    fn checksum_formats() {
+        let cases = vec![
+            (UBUNTU_SUMS, Algorithm::from_file_name("SHA256SUMS"), vec![
+                ("ubuntu-24.04-desktop-amd64.iso", Algorithm::Sha256, "ca978112ca1bbdcafac231b39a23dc4da786eff8147c4e72b9807785afee48bb"),
+                ("ubuntu-24.04-live-server-amd64.iso", Algorithm::Sha256, "3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d"),
+            ]),
+            // Comments, armour and dash-escaping are skipped, and digests are lowercased.
+            (FEDORA_CHECKSUM, None, vec![
+                ("Fedora-Workstation-Live-osb-40-1.14.x86_64.iso", Algorithm::Sha256, "18ac3e7343f016890c510e93f935261169d9e3f565436429830faf0934f4f8e4"),
+                ("Fedora-Workstation-Live-x86_64-40-1.14.iso", Algorithm::Sha256, "2e7d2c03a9507ae265ecf5b5356885a53393a2029d241394997265a1a25aefc6"),
+            ]),
+            (KDE_NEON_SUMS, Algorithm::from_file_name("neon-user-current.sha256sum"), vec![
+                ("neon-user-20240613-0744.iso", Algorithm::Sha256, "3f79bb7b435b05321651daefd374cdc681dc06faa65e374e38337b88ca046dea"),
+            ]),
+            (FREEBSD_CHECKSUM, None, vec![
+                ("FreeBSD-14.1-RELEASE-amd64-disc1.iso", Algorithm::Sha512, "a4abd4448c49562d828115d13a1fccea927f52b4d5459297f8b43e42da89238bc13626e43dcb38ddb082488927ec904fb42057443983e88585179d50551afe62"),
+            ]),
+            // OpenSSL's form of the BSD format.
+            ("SHA2-256(disk.img)= 252f10c83610ebca1a059c0bae8255eba2f95be4d1d7bcfa89d7248a82d9f111\n", None, vec![
+                ("disk.img", Algorithm::Sha256, "252f10c83610ebca1a059c0bae8255eba2f95be4d1d7bcfa89d7248a82d9f111"),
+            ]),
+            // Coreutils escaping, a "./" prefix, and a name which looks like the BSD format.
+            ("\\252f10c83610ebca1a059c0bae8255eba2f95be4d1d7bcfa89d7248a82d9f111  back\\\\slash\\nnewline.iso\n\
+              cd0aa9856147b6c5b4ff2b7dfee5da20aa38253099ef1b4a64aced233c9afe29  ./images/disc (1).iso\n",
+                Some(Algorithm::Blake2b), vec![
+                ("back\\slash\nnewline.iso", Algorithm::Blake2b, "252f10c83610ebca1a059c0bae8255eba2f95be4d1d7bcfa89d7248a82d9f111"),
+                ("images/disc (1).iso", Algorithm::Blake2b, "cd0aa9856147b6c5b4ff2b7dfee5da20aa38253099ef1b4a64aced233c9afe29"),
+            ]),
+            // Without an algorithm, lines which don't name theirs can't be used.
+            (UBUNTU_SUMS, None, vec![]),
+        ];
+        for (contents, algorithm, expected) in cases {
+            let expected = expected.into_iter()
+                .map(|(file, algorithm, digest)| (file.to_string(), (algorithm, digest.to_string())))
+                .collect::<BTreeMap<String, Hash>>();
+            assert_eq!(parse_checksums(contents, algorithm), expected, "{}", contents);
+        }
+    }
+
+    #[test]
+    fn checksum_lookup() {
+        let single = checksum_from("https://example.org/image.iso.sha3-256", "CD0AA9856147B6C5B4FF2B7DFEE5DA20AA38253099EF1B4A64ACED233C9AFE29\n", |file| file == "image.iso");
+        let expected = (Algorithm::Sha3_256, "cd0aa9856147b6c5b4ff2b7dfee5da20aa38253099ef1b4a64aced233c9afe29".to_string());
+        assert_eq!(single.unwrap(), (String::new(), expected));
+        let sums = "https://releases.ubuntu.com/24.04/SHA256SUMS";
+        let server = checksum_from(sums, UBUNTU_SUMS, |file| file.contains("server")).unwrap();
+        assert_eq!(server.0, "ubuntu-24.04-live-server-amd64.iso");
+        assert!(checksum_from(sums, UBUNTU_SUMS, |file| file == "image.iso").is_err());
+        // Only the file's own name gives the algorithm, not the mirror's path.
+        let md5 = checksum_from("https://mirror.example/sha512/MD5SUMS", "d41d8cd98f00b204e9800998ecf8427e *disk.img\n", |_| true);
+        assert_eq!(md5.unwrap().1.0, Algorithm::Md5);
+    }
+}